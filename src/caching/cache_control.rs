@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use httpdate::parse_http_date;
+
+/// Directives of a `Cache-Control` header. Directive names are case-insensitive, values may be quoted.
+pub struct CacheControl {
+    directives: HashMap<String, Option<String>>,
+}
+
+impl CacheControl {
+
+    pub fn parse(value: &str) -> Self {
+        let directives = value.split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                let mut parts = v.splitn(2, '=');
+                let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+                let value = parts.next().map(|v| v.trim().trim_matches('"').to_string());
+                (name, value)
+            })
+            .collect();
+
+        Self {
+            directives
+        }
+    }
+
+    pub fn from_headers(headers: &HashMap<String, String>) -> Self {
+        Self::parse(headers.get("cache-control").map(|v| v.as_str()).unwrap_or(""))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.directives.contains_key(name)
+    }
+
+    /// Whether a shared cache may store the response without revalidating it on every request.
+    pub fn is_cacheable(&self) -> bool {
+        !self.has("no-store") && !self.has("private") && !self.has("no-cache")
+    }
+
+    /// Freshness lifetime for shared caches, `s-maxage` takes precedence over `max-age`.
+    pub fn max_age(&self) -> Option<u64> {
+        self.seconds("s-maxage").or_else(|| self.seconds("max-age"))
+    }
+
+    /// Value of a delta-seconds directive like `max-age=60`.
+    pub fn seconds(&self, name: &str) -> Option<u64> {
+        self.directives.get(name)?.as_ref()?.parse().ok()
+    }
+}
+
+/// Freshness lifetime given by the `Expires` header, relative to `Date` if the origin sent one.
+/// An invalid `Expires` value means the response is already expired.
+pub fn expires_ttl(headers: &HashMap<String, String>) -> Option<u64> {
+    let expires = headers.get("expires")?;
+    let expires = match parse_http_date(expires) {
+        Ok(v) => v,
+        Err(_) => return Some(0),
+    };
+
+    let date = headers.get("date")
+        .and_then(|v| parse_http_date(v).ok())
+        .unwrap_or_else(SystemTime::now);
+
+    Some(expires.duration_since(date).map(|v| v.as_secs()).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    #[test]
+    fn s_maxage_takes_precedence() {
+        assert_eq!(CacheControl::parse("max-age=60, s-maxage=3600").max_age(), Some(3600));
+        assert_eq!(CacheControl::parse("s-maxage=0, max-age=60").max_age(), Some(0));
+        assert_eq!(CacheControl::parse("public, max-age=60").max_age(), Some(60));
+        assert_eq!(CacheControl::parse("public").max_age(), None);
+    }
+
+    #[test]
+    fn parses_directives_case_insensitively() {
+        let cache_control = CacheControl::parse("Public, MAX-AGE=\"120\", stale-while-revalidate=30");
+
+        assert!(cache_control.has("public"));
+        assert_eq!(cache_control.max_age(), Some(120));
+        assert_eq!(cache_control.seconds("stale-while-revalidate"), Some(30));
+        assert_eq!(cache_control.seconds("stale-if-error"), None);
+        assert_eq!(CacheControl::parse("max-age=soon").max_age(), None);
+    }
+
+    #[test]
+    fn detects_uncacheable_responses() {
+        assert!(CacheControl::parse("public, max-age=60").is_cacheable());
+        assert!(CacheControl::parse("").is_cacheable());
+        assert!(!CacheControl::parse("no-store").is_cacheable());
+        assert!(!CacheControl::parse("private, max-age=60").is_cacheable());
+        assert!(!CacheControl::parse("no-cache").is_cacheable());
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let result = headers(&[
+            ("date", "Sun, 13 Sep 2020 12:00:00 GMT"),
+            ("expires", "Sun, 13 Sep 2020 13:00:00 GMT"),
+        ]);
+        assert_eq!(expires_ttl(&result), Some(3600));
+
+        let expired = headers(&[
+            ("date", "Sun, 13 Sep 2020 12:00:00 GMT"),
+            ("expires", "Sun, 13 Sep 2020 11:00:00 GMT"),
+        ]);
+        assert_eq!(expires_ttl(&expired), Some(0));
+    }
+
+    #[test]
+    fn invalid_expires_means_expired() {
+        assert_eq!(expires_ttl(&headers(&[("expires", "0")])), Some(0));
+        assert_eq!(expires_ttl(&headers(&[("expires", "Thu, 01 Jan 1970 00:00:00 GMT")])), Some(0));
+        assert_eq!(expires_ttl(&HashMap::new()), None);
+    }
+}
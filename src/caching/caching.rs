@@ -2,13 +2,17 @@ use custom_error::custom_error;
 use std::collections::HashMap;
 use crate::caching::local::LocalCache;
 use crate::caching::redis::RedisCache;
-use crate::caching::messages::{CacheError, PutCacheEntry, GetCacheEntry, CacheEntry};
+use crate::caching::tiered::TieredCache;
+use crate::caching::disk::DiskCache;
+use crate::caching::memcached::MemcachedCache;
+use crate::caching::messages::{CacheError, PutCacheEntry, GetCacheEntry, DeleteCacheEntry, CacheEntry};
 use crate::config;
 use actix::{Actor, Addr};
 
 custom_error!{pub CacheInstantiationError
     MissingField { field_name: String } = "missing field: {field_name}",
     NotImplemented { cache_type: String } = "cache not implemented: {cache_type}",
+    UnknownTier { name: String } = "tier is not a configured non-tiered cache: {name}",
     CacheError { source: CacheError } = "cache error: {}"
 }
 
@@ -20,7 +24,8 @@ impl Caching {
     pub async fn new(config: &HashMap<String, config::Caching>) -> Self {
         let mut caches = HashMap::new();
 
-        for cache_config in config {
+        // tiered caches are made last, they are composed of the other caches
+        for cache_config in config.iter().filter(|v| !v.1.is_tiered()) {
             let cache = match Self::make_cache(cache_config.1).await {
                 Ok(v) => v,
                 Err(err) => {
@@ -31,6 +36,17 @@ impl Caching {
             caches.insert(cache_config.0.clone(), cache);
         }
 
+        for cache_config in config.iter().filter(|v| v.1.is_tiered()) {
+            let cache = match Self::make_tiered_cache(cache_config.0, cache_config.1, &caches) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to make cache {}: {}", cache_config.0, err);
+                    continue;
+                }
+            };
+            caches.insert(cache_config.0.clone(), cache);
+        }
+
         Caching {
             caches
         }
@@ -40,15 +56,54 @@ impl Caching {
         match &config.caching_type {
             Some(v) => match &v as &str {
                 "local" => Ok(CacheInstance::LocalCache(LocalCache::new(config.capacity, config.ttl).start())),
-                "redis" => Ok(CacheInstance::Redis(
-                    RedisCache::new(config.host.clone().unwrap(), config.port.unwrap(), config.ttl).await?.start()
-                )),
+                "redis" => {
+                    let (host, port) = Self::host_and_port(config)?;
+                    Ok(CacheInstance::Redis(RedisCache::new(host, port, config.ttl).await?.start()))
+                },
+                "memcached" => {
+                    let (host, port) = Self::host_and_port(config)?;
+                    Ok(CacheInstance::Memcached(
+                        MemcachedCache::new(host, port, config.ttl, config.max_item_size).await?.start()
+                    ))
+                },
+                "disk" => match &config.directory {
+                    Some(directory) => Ok(CacheInstance::Disk(
+                        DiskCache::new(directory, config.max_size, config.ttl)?.start()
+                    )),
+                    None => Err(CacheInstantiationError::MissingField { field_name: "directory".to_string() }),
+                },
                 cache_type => Err(CacheInstantiationError::NotImplemented { cache_type: cache_type.to_string() })
             },
             None => Err(CacheInstantiationError::MissingField { field_name: "caching_type".to_string() })
         }
     }
 
+    fn host_and_port(config: &config::Caching) -> Result<(String, u16), CacheInstantiationError> {
+        let host = config.host.clone()
+            .ok_or_else(|| CacheInstantiationError::MissingField { field_name: "host".to_string() })?;
+        let port = config.port
+            .ok_or_else(|| CacheInstantiationError::MissingField { field_name: "port".to_string() })?;
+        Ok((host, port))
+    }
+
+    fn make_tiered_cache(
+        name: &str,
+        config: &config::Caching,
+        caches: &HashMap<String, CacheInstance>,
+    ) -> Result<CacheInstance, CacheInstantiationError> {
+        let tier_names = config.tiers.as_ref()
+            .ok_or_else(|| CacheInstantiationError::MissingField { field_name: "tiers".to_string() })?;
+
+        let tiers = tier_names.iter()
+            .map(|v| match caches.get(v) {
+                Some(cache) => Ok((v.clone(), cache.clone())),
+                None => Err(CacheInstantiationError::UnknownTier { name: v.clone() }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CacheInstance::Tiered(TieredCache::new(name.to_string(), tiers).start()))
+    }
+
     pub fn get_cache(&self, name: &str) -> Option<CacheInstance> {
         (&self.caches.get(name).map(|v| v.clone())).clone()
     }
@@ -58,6 +113,9 @@ impl Caching {
 pub enum CacheInstance {
     LocalCache(Addr<LocalCache>),
     Redis(Addr<RedisCache>),
+    Tiered(Addr<TieredCache>),
+    Disk(Addr<DiskCache>),
+    Memcached(Addr<MemcachedCache>),
 }
 
 impl CacheInstance {
@@ -65,14 +123,30 @@ impl CacheInstance {
     pub async fn send_put_message(&self, msg: PutCacheEntry) -> Result<(), CacheError> {
         match &self {
             Self::LocalCache(addr) => addr.send(msg).await?,
-            Self::Redis(addr) => addr.send(msg).await?
+            Self::Redis(addr) => addr.send(msg).await?,
+            Self::Tiered(addr) => addr.send(msg).await?,
+            Self::Disk(addr) => addr.send(msg).await?,
+            Self::Memcached(addr) => addr.send(msg).await?
         }
     }
 
     pub async fn send_get_message(&self, msg: GetCacheEntry) -> Result<CacheEntry, CacheError> {
         match &self {
             Self::LocalCache(addr) => addr.send(msg).await?,
-            Self::Redis(addr) => addr.send(msg).await?
+            Self::Redis(addr) => addr.send(msg).await?,
+            Self::Tiered(addr) => addr.send(msg).await?,
+            Self::Disk(addr) => addr.send(msg).await?,
+            Self::Memcached(addr) => addr.send(msg).await?
+        }
+    }
+
+    pub async fn send_delete_message(&self, msg: DeleteCacheEntry) -> Result<(), CacheError> {
+        match &self {
+            Self::LocalCache(addr) => addr.send(msg).await?,
+            Self::Redis(addr) => addr.send(msg).await?,
+            Self::Tiered(addr) => addr.send(msg).await?,
+            Self::Disk(addr) => addr.send(msg).await?,
+            Self::Memcached(addr) => addr.send(msg).await?
         }
     }
 }
\ No newline at end of file
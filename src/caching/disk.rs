@@ -0,0 +1,463 @@
+use actix::{Context, Handler, Actor, ResponseFuture};
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use prometheus::{Gauge, Counter, register_gauge, register_counter};
+use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheError, entry_key, unix_now};
+use crate::digest::sha256_hex;
+
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+// files are written here first and renamed into place once complete
+const TEMP_DIRECTORY: &str = "tmp";
+
+// temporary files are removed on startup, so their names only have to be unique within the process
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref DISK_CACHE_SIZE: Gauge = register_gauge!(
+        "disk_cache_size",
+        "disk cache size in bytes"
+    ).unwrap();
+    static ref DISK_CACHE_GET: Counter = register_counter!(
+        "disk_cache_get",
+        "disk cache get operations"
+    ).unwrap();
+    static ref DISK_CACHE_PUT: Counter = register_counter!(
+        "disk_cache_put",
+        "disk cache put operations"
+    ).unwrap();
+    static ref DISK_CACHE_DELETE: Counter = register_counter!(
+        "disk_cache_delete",
+        "disk cache delete operations"
+    ).unwrap();
+    static ref DISK_CACHE_EVICTIONS: Counter = register_counter!(
+        "disk_cache_evictions",
+        "entries evicted from the disk cache to stay within its size budget"
+    ).unwrap();
+}
+
+/// Everything but the body of an entry, stored as a json line in front of the body.
+#[derive(Serialize, Deserialize)]
+struct EntryMetadata {
+    expires_at: u64,
+    size: u64,
+    entry: CacheEntry,
+}
+
+struct IndexEntry {
+    size: u64,
+    expires_at: u64,
+    last_access: u64,
+}
+
+/// Entries on disk by file name, with their least recently used order.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl Index {
+
+    fn insert(&mut self, name: String, size: u64, expires_at: u64) {
+        self.remove(&name);
+
+        self.tick += 1;
+        self.lru.insert(self.tick, name.clone());
+        self.size += size;
+        self.entries.insert(name, IndexEntry {
+            size,
+            expires_at,
+            last_access: self.tick,
+        });
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some(v) => {
+                self.lru.remove(&v.last_access);
+                self.size -= v.size;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn touch(&mut self, name: &str) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            self.lru.remove(&entry.last_access);
+            self.tick += 1;
+            entry.last_access = self.tick;
+            self.lru.insert(self.tick, name.to_string());
+        }
+    }
+
+    /// Removes least recently used entries until the index fits into `max_size`.
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while self.size > max_size {
+            let name = match self.lru.values().next() {
+                Some(v) => v.clone(),
+                None => break,
+            };
+            self.remove(&name);
+            evicted.push(name);
+        }
+
+        evicted
+    }
+}
+
+/// Stores every entry as a single file in a directory, named after the hash of its key. Files are
+/// renamed into place once written, so readers never see partial entries. Other files in the
+/// directory are left alone.
+pub struct DiskCache {
+    directory: PathBuf,
+    max_size: u64,
+    ttl: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+impl DiskCache {
+    pub fn new(directory: &str, max_size: Option<u64>, ttl: Option<u64>) -> Result<Self, CacheError> {
+        let directory = PathBuf::from(directory);
+        let max_size = max_size.unwrap_or(DEFAULT_MAX_SIZE);
+        std::fs::create_dir_all(directory.join(TEMP_DIRECTORY))?;
+
+        let mut index = Self::rebuild_index(&directory)?;
+
+        // the size budget may have been lowered since the previous run
+        for name in index.evict(max_size) {
+            std::fs::remove_file(directory.join(&name))?;
+        }
+
+        DISK_CACHE_SIZE.set(index.size as f64);
+        info!("disk cache in {} has {} entries, {} bytes", directory.display(), index.entries.len(), index.size);
+
+        Ok(Self {
+            directory,
+            max_size,
+            ttl: ttl.unwrap_or(3600),
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+
+    /// Reads the metadata of all entries left by a previous run, removing expired, invalid and temporary files.
+    fn rebuild_index(directory: &Path) -> Result<Index, CacheError> {
+        for file in std::fs::read_dir(directory.join(TEMP_DIRECTORY))? {
+            let path = file?.path();
+            if path.file_stem().and_then(|v| v.to_str()).map(is_entry_name).unwrap_or(false) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        let now = unix_now();
+        let mut entries = Vec::new();
+
+        for file in std::fs::read_dir(directory)? {
+            let file = file?;
+            let name = match file.file_name().to_str() {
+                Some(v) if is_entry_name(v) => v.to_string(),
+                _ => continue,
+            };
+            if !file.file_type()?.is_file() {
+                continue;
+            }
+
+            let path = file.path();
+            let file_size = file.metadata()?.len();
+
+            match read_metadata(&path) {
+                Ok((metadata, metadata_size)) if metadata.expires_at > now && metadata_size + metadata.size == file_size => {
+                    let modified = file.metadata()?.modified()?;
+                    entries.push((modified, name, metadata));
+                },
+                _ => {
+                    debug!("removing expired or invalid disk cache entry {}", name);
+                    std::fs::remove_file(&path)?;
+                },
+            }
+        }
+
+        // entries written most recently are considered most recently used
+        entries.sort_by_key(|v| v.0);
+
+        let mut index = Index::default();
+        for (_, name, metadata) in entries {
+            index.insert(name, metadata.size, metadata.expires_at);
+        }
+
+        Ok(index)
+    }
+}
+
+impl Actor for DiskCache {
+    type Context = Context<Self>;
+}
+
+impl Handler<PutCacheEntry> for DiskCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: PutCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let directory = self.directory.clone();
+        let index = self.index.clone();
+        let max_size = self.max_size;
+        let ttl = msg.ttl.unwrap_or(self.ttl);
+
+        DISK_CACHE_PUT.inc();
+
+        Box::pin(async move {
+            let name = file_name(&msg.bucket, &entry_key(&msg.key, msg.generation));
+            let (entry, body) = msg.entry.split_body();
+            let size = body.len() as u64;
+
+            if size > max_size {
+                debug!("entry is larger than the disk cache, not caching it");
+                return Ok(());
+            }
+
+            let mut contents = serde_json::to_vec(&EntryMetadata {
+                expires_at: unix_now().saturating_add(ttl),
+                size,
+                entry,
+            })?;
+            contents.push(b'\n');
+            contents.extend(body);
+
+            write_atomically(&directory, &name, &contents).await?;
+
+            let evicted = {
+                let mut index = index.lock().unwrap();
+                index.insert(name, size, unix_now().saturating_add(ttl));
+                let evicted = index.evict(max_size);
+                DISK_CACHE_SIZE.set(index.size as f64);
+                evicted
+            };
+
+            for name in evicted {
+                DISK_CACHE_EVICTIONS.inc();
+                remove_file(&directory, &name).await;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Handler<GetCacheEntry> for DiskCache {
+    type Result = ResponseFuture<Result<CacheEntry, CacheError>>;
+
+    fn handle(&mut self, msg: GetCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let directory = self.directory.clone();
+        let index = self.index.clone();
+
+        DISK_CACHE_GET.inc();
+
+        Box::pin(async move {
+            let name = file_name(&msg.bucket, &entry_key(&msg.key, msg.generation));
+
+            let is_expired = {
+                let mut index = index.lock().unwrap();
+                let is_expired = match index.entries.get(&name) {
+                    Some(v) => v.expires_at <= unix_now(),
+                    None => return Err(CacheError::FailedToGetKey { reason: "Key not present".to_string() }),
+                };
+
+                if is_expired {
+                    index.remove(&name);
+                    DISK_CACHE_SIZE.set(index.size as f64);
+                } else {
+                    index.touch(&name);
+                }
+                is_expired
+            };
+
+            if is_expired {
+                remove_file(&directory, &name).await;
+                return Err(CacheError::FailedToGetKey { reason: "Key expired".to_string() });
+            }
+
+            match read_entry(&directory, &name).await {
+                Ok(v) => Ok(v),
+                Err(err) => {
+                    warn!("failed to read disk cache entry {}: {}", name, err);
+                    let mut index = index.lock().unwrap();
+                    index.remove(&name);
+                    DISK_CACHE_SIZE.set(index.size as f64);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+impl Handler<DeleteCacheEntry> for DiskCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: DeleteCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let directory = self.directory.clone();
+        let index = self.index.clone();
+
+        DISK_CACHE_DELETE.inc();
+
+        Box::pin(async move {
+            let name = file_name(&msg.bucket, &msg.key);
+
+            let is_removed = {
+                let mut index = index.lock().unwrap();
+                let is_removed = index.remove(&name);
+                DISK_CACHE_SIZE.set(index.size as f64);
+                is_removed
+            };
+
+            if is_removed {
+                remove_file(&directory, &name).await;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+async fn read_entry(directory: &Path, name: &str) -> Result<CacheEntry, CacheError> {
+    let contents = tokio::fs::read(directory.join(name)).await?;
+    let separator = contents.iter().position(|v| *v == b'\n')
+        .ok_or_else(|| CacheError::FailedToGetKey { reason: "invalid entry".to_string() })?;
+    let metadata: EntryMetadata = serde_json::from_slice(&contents[..separator])?;
+
+    Ok(metadata.entry.with_body(contents[separator + 1..].to_vec()))
+}
+
+/// Reads the metadata line of an entry file, returning it with its size including the separator.
+fn read_metadata(path: &Path) -> Result<(EntryMetadata, u64), CacheError> {
+    let mut line = Vec::new();
+    std::io::BufReader::new(std::fs::File::open(path)?).read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(CacheError::FailedToGetKey { reason: "invalid entry".to_string() });
+    }
+
+    Ok((serde_json::from_slice(&line)?, line.len() as u64 + 1))
+}
+
+async fn write_atomically(directory: &Path, name: &str, contents: &[u8]) -> Result<(), CacheError> {
+    let temp_name = format!("{}.{}", name, TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed));
+    let temp_path = directory.join(TEMP_DIRECTORY).join(temp_name);
+    if let Err(err) = write_and_rename(&temp_path, &directory.join(name), contents).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+async fn write_and_rename(temp_path: &Path, path: &Path, contents: &[u8]) -> Result<(), CacheError> {
+    tokio::fs::write(temp_path, contents).await?;
+    tokio::fs::rename(temp_path, path).await?;
+    Ok(())
+}
+
+async fn remove_file(directory: &Path, name: &str) {
+    let path = directory.join(name);
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+        debug!("failed to remove disk cache file {}: {}", path.display(), err);
+    }
+}
+
+fn file_name(bucket: &str, key: &str) -> String {
+    sha256_hex(format!("{}:{}", bucket, key).as_bytes())
+}
+
+/// Entry files are named after a sha256 hash, everything else in the directory is not ours.
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|v| matches!(v, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(entries: &[(&str, u64)]) -> Index {
+        let mut index = Index::default();
+        for (name, size) in entries {
+            index.insert(name.to_string(), *size, u64::MAX);
+        }
+        index
+    }
+
+    /// Contents of an entry file with the given body, of which only `written` bytes made it to disk.
+    fn entry_file(expires_at: u64, body: &[u8], written: usize) -> Vec<u8> {
+        let mut contents = serde_json::to_vec(&EntryMetadata {
+            expires_at,
+            size: body.len() as u64,
+            entry: CacheEntry::from_body_and_headers(Vec::new(), HashMap::new()),
+        }).unwrap();
+        contents.push(b'\n');
+        contents.extend(&body[..written]);
+        contents
+    }
+
+    #[test]
+    fn evicts_least_recently_accessed_entries() {
+        let mut index = index(&[("a", 10), ("b", 10), ("c", 10)]);
+
+        index.touch("a");
+        assert_eq!(index.evict(20), vec!["b"]);
+        index.insert("d".to_string(), 10, u64::MAX);
+        assert_eq!(index.evict(10), vec!["c", "a"]);
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), vec!["d"]);
+    }
+
+    #[test]
+    fn tracks_size_on_replace_and_remove() {
+        let mut index = index(&[("a", 10), ("b", 5)]);
+        assert_eq!(index.size, 15);
+
+        index.insert("a".to_string(), 3, u64::MAX);
+        assert_eq!(index.size, 8);
+        assert_eq!(index.lru.len(), 2);
+
+        assert!(index.remove("b"));
+        assert!(!index.remove("b"));
+        assert_eq!(index.size, 3);
+        assert_eq!(index.evict(3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rebuilds_index_from_valid_entries_only() {
+        let directory = std::env::temp_dir().join(format!("cloud-storage-proxy-disk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join(TEMP_DIRECTORY)).unwrap();
+
+        let valid = file_name("bucket", "valid");
+        let truncated = file_name("bucket", "truncated");
+        let expired = file_name("bucket", "expired");
+        let invalid = file_name("bucket", "invalid");
+        let temporary = format!("{}.0", file_name("bucket", "temporary"));
+        std::fs::write(directory.join(&valid), entry_file(u64::MAX, b"body", 4)).unwrap();
+        std::fs::write(directory.join(&truncated), entry_file(u64::MAX, b"body", 2)).unwrap();
+        std::fs::write(directory.join(&expired), entry_file(1, b"body", 4)).unwrap();
+        std::fs::write(directory.join(&invalid), b"not an entry").unwrap();
+        std::fs::write(directory.join(TEMP_DIRECTORY).join(&temporary), b"partial").unwrap();
+        // files not named like entries are not ours, whatever they contain
+        std::fs::write(directory.join("notes.txt"), entry_file(1, b"body", 2)).unwrap();
+        std::fs::write(directory.join(TEMP_DIRECTORY).join("notes.txt"), b"notes").unwrap();
+
+        let index = DiskCache::rebuild_index(&directory).unwrap();
+
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), vec![&valid]);
+        assert_eq!(index.size, 4);
+        for name in &[&truncated, &expired, &invalid] {
+            assert!(!directory.join(name).exists(), "{} was kept", name);
+        }
+        assert!(!directory.join(TEMP_DIRECTORY).join(&temporary).exists());
+        assert!(directory.join("notes.txt").exists());
+        assert!(directory.join(TEMP_DIRECTORY).join("notes.txt").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
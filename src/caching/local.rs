@@ -4,7 +4,7 @@ use std::time::Duration;
 
 use prometheus::{Gauge, Counter, register_gauge, register_counter};
 
-use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, CacheError};
+use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheError, entry_key};
 
 lazy_static! {
     static ref LOCAL_CACHE_SIZE: Gauge = register_gauge!(
@@ -19,6 +19,10 @@ lazy_static! {
         "local_cache_put",
         "local cache put operations"
     ).unwrap();
+    static ref LOCAL_CACHE_DELETE: Counter = register_counter!(
+        "local_cache_delete",
+        "local cache delete operations"
+    ).unwrap();
 }
 
 pub struct LocalCache {
@@ -48,9 +52,9 @@ impl Handler<PutCacheEntry> for LocalCache {
         LOCAL_CACHE_PUT.inc();
 
         self.cache.insert(
-            msg.key.into(),
+            format!("{}:{}", msg.bucket, entry_key(&msg.key, msg.generation)),
             msg.entry,
-            self.ttl.clone(),
+            msg.ttl.map(Duration::from_secs).unwrap_or(self.ttl),
         );
         Ok(())
     }
@@ -60,7 +64,7 @@ impl Handler<GetCacheEntry> for LocalCache {
     type Result = ResponseFuture<Result<CacheEntry, CacheError>>;
 
     fn handle(&mut self, msg: GetCacheEntry, _: &mut Context<Self>) -> Self::Result {
-        let key = msg.key.clone();
+        let key = format!("{}:{}", msg.bucket, entry_key(&msg.key, msg.generation));
         let cache = self.cache.clone();
 
         LOCAL_CACHE_GET.inc();
@@ -72,3 +76,15 @@ impl Handler<GetCacheEntry> for LocalCache {
         })
     }
 }
+
+impl Handler<DeleteCacheEntry> for LocalCache {
+    type Result = Result<(), CacheError>;
+
+    fn handle(&mut self, msg: DeleteCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        LOCAL_CACHE_DELETE.inc();
+
+        self.cache.remove(&format!("{}:{}", msg.bucket, msg.key));
+        LOCAL_CACHE_SIZE.set(self.cache.iter().count() as f64);
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+use actix::{Context, Handler, Actor, ResponseFuture};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use prometheus::{Counter, register_counter};
+use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheError, entry_key, unix_now};
+use crate::digest::sha256_hex;
+
+const KEY_PREFIX: &str = "cloud_storage_proxy";
+// memcached refuses items larger than 1 MiB by default, including some bytes for the key and flags
+const DEFAULT_MAX_ITEM_SIZE: usize = 1024 * 1024 - 1024;
+// entries needing more items than this are not cached
+const MAX_CHUNKS: usize = 64;
+// expiry times longer than this are taken as unix timestamps by memcached
+const MAX_RELATIVE_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+const FLAG_WHOLE: u32 = 0;
+const FLAG_CHUNKED: u32 = 1;
+
+lazy_static! {
+    static ref MEMCACHED_CACHE_GET: Counter = register_counter!(
+        "memcached_cache_get",
+        "memcached cache get operations"
+    ).unwrap();
+    static ref MEMCACHED_CACHE_PUT: Counter = register_counter!(
+        "memcached_cache_put",
+        "memcached cache put operations"
+    ).unwrap();
+    static ref MEMCACHED_CACHE_DELETE: Counter = register_counter!(
+        "memcached_cache_delete",
+        "memcached cache delete operations"
+    ).unwrap();
+    static ref MEMCACHED_CACHE_ERRORS: Counter = register_counter!(
+        "memcached_cache_errors",
+        "memcached connection and protocol errors"
+    ).unwrap();
+    static ref MEMCACHED_CACHE_SKIPPED: Counter = register_counter!(
+        "memcached_cache_skipped",
+        "entries too large to be stored in memcached"
+    ).unwrap();
+}
+
+/// Talks the memcached text protocol. Entries are stored as their metadata followed by the body,
+/// entries larger than one item are split into chunks referenced from the main item.
+pub struct MemcachedCache {
+    address: String,
+    connection: Arc<Mutex<Option<BufReader<TcpStream>>>>,
+    ttl: u64,
+    max_item_size: usize,
+}
+
+impl MemcachedCache {
+    pub async fn new(host: String, port: u16, ttl: Option<u64>, max_item_size: Option<usize>) -> Result<Self, CacheError> {
+        let address = format!("{}:{}", &host, &port);
+        let connection = connect(&address).await?;
+
+        Ok(Self {
+            address,
+            connection: Arc::new(Mutex::new(Some(connection))),
+            ttl: ttl.unwrap_or(3600),
+            max_item_size: max_item_size.unwrap_or(DEFAULT_MAX_ITEM_SIZE),
+        })
+    }
+}
+
+impl Actor for MemcachedCache {
+    type Context = Context<Self>;
+}
+
+impl Handler<PutCacheEntry> for MemcachedCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: PutCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let address = self.address.clone();
+        let connection = self.connection.clone();
+        let ttl = msg.ttl.unwrap_or(self.ttl);
+        let max_item_size = self.max_item_size;
+
+        MEMCACHED_CACHE_PUT.inc();
+
+        Box::pin(async move {
+            let key = item_key(&msg.bucket, &entry_key(&msg.key, msg.generation));
+            let value = encode_entry(msg.entry)?;
+
+            let chunk_count = value.len().div_ceil(max_item_size);
+            if chunk_count > MAX_CHUNKS {
+                MEMCACHED_CACHE_SKIPPED.inc();
+                debug!("entry is too large for memcached, not caching it");
+                return Ok(());
+            }
+
+            let mut connection = connection.lock().await;
+            let result = match connected(&mut connection, &address).await {
+                Ok(c) => put_item(c, &key, &value, expiry_time(ttl), max_item_size).await,
+                Err(err) => Err(err),
+            };
+            release_on_error(&mut connection, &result);
+            result
+        })
+    }
+}
+
+impl Handler<GetCacheEntry> for MemcachedCache {
+    type Result = ResponseFuture<Result<CacheEntry, CacheError>>;
+
+    fn handle(&mut self, msg: GetCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let address = self.address.clone();
+        let connection = self.connection.clone();
+
+        MEMCACHED_CACHE_GET.inc();
+
+        Box::pin(async move {
+            let key = item_key(&msg.bucket, &entry_key(&msg.key, msg.generation));
+
+            let mut connection = connection.lock().await;
+            let result = match connected(&mut connection, &address).await {
+                Ok(c) => get_item(c, &key).await,
+                Err(err) => Err(err),
+            };
+            release_on_error(&mut connection, &result);
+
+            match result? {
+                Some(v) => decode_entry(&v),
+                None => Err(CacheError::FailedToGetKey { reason: "Key not present".to_string() }),
+            }
+        })
+    }
+}
+
+impl Handler<DeleteCacheEntry> for MemcachedCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: DeleteCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let address = self.address.clone();
+        let connection = self.connection.clone();
+
+        MEMCACHED_CACHE_DELETE.inc();
+
+        Box::pin(async move {
+            let key = item_key(&msg.bucket, &msg.key);
+
+            let mut connection = connection.lock().await;
+            let result = match connected(&mut connection, &address).await {
+                Ok(c) => delete_item(c, &key).await,
+                Err(err) => Err(err),
+            };
+            release_on_error(&mut connection, &result);
+            result
+        })
+    }
+}
+
+type Connection = BufReader<TcpStream>;
+
+async fn connect(address: &str) -> Result<Connection, CacheError> {
+    let stream = TcpStream::connect(address).await
+        .map_err(|err| CacheError::FailedToCreateCacheClient { reason: format!("failed to connect to memcached: {}", err) })?;
+    stream.set_nodelay(true)?;
+    Ok(BufReader::new(stream))
+}
+
+/// The open connection, reconnecting if a previous request failed.
+async fn connected<'a>(connection: &'a mut Option<Connection>, address: &str) -> Result<&'a mut Connection, CacheError> {
+    if connection.is_none() {
+        *connection = Some(connect(address).await?);
+    }
+    Ok(connection.as_mut().unwrap())
+}
+
+/// Drops the connection after a failed request, its state is unknown then.
+fn release_on_error<T>(connection: &mut Option<Connection>, result: &Result<T, CacheError>) {
+    if let Err(err) = result {
+        MEMCACHED_CACHE_ERRORS.inc();
+        warn!("memcached request failed: {}", err);
+        *connection = None;
+    }
+}
+
+async fn put_item(c: &mut Connection, key: &str, value: &[u8], exptime: u64, max_item_size: usize) -> Result<(), CacheError> {
+    if value.len() <= max_item_size {
+        return set(c, key, FLAG_WHOLE, exptime, value).await;
+    }
+
+    // chunks are stored under a new random version on every put, so readers never mix chunks of
+    // different writes, even from other proxy instances
+    let mut version = [0u8; 8];
+    openssl::rand::rand_bytes(&mut version)
+        .map_err(|err| CacheError::FailedToGetKey { reason: format!("failed to generate chunk version: {}", err) })?;
+    let version = u64::from_le_bytes(version);
+
+    let chunks: Vec<&[u8]> = value.chunks(max_item_size).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        set(c, &chunk_key(key, version, i), FLAG_WHOLE, exptime, chunk).await?;
+    }
+
+    // the main item is written last, so readers only find complete entries
+    let manifest = format!("{} {}", version, chunks.len());
+    set(c, key, FLAG_CHUNKED, exptime, manifest.as_bytes()).await
+}
+
+async fn get_item(c: &mut Connection, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+    let (flags, value) = match get(c, &[key.to_string()]).await?.remove(key) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if flags != FLAG_CHUNKED {
+        return Ok(Some(value));
+    }
+
+    let manifest = String::from_utf8_lossy(&value).to_string();
+    let (version, chunk_count) = parse_manifest(&manifest).ok_or_else(|| protocol_error(&manifest))?;
+
+    let chunk_keys: Vec<String> = (0..chunk_count).map(|i| chunk_key(key, version, i)).collect();
+    let mut chunks = get(c, &chunk_keys).await?;
+
+    // chunks are evicted independently, the entry is gone if any of them is
+    let mut value = Vec::new();
+    for chunk_key in &chunk_keys {
+        match chunks.remove(chunk_key) {
+            Some(v) => value.extend(v.1),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(value))
+}
+
+// chunks are left to expire, they are unreachable without the main item
+async fn delete_item(c: &mut Connection, key: &str) -> Result<(), CacheError> {
+    match command(c, format!("delete {}\r\n", key).as_bytes()).await?.as_str() {
+        "DELETED" | "NOT_FOUND" => Ok(()),
+        response => Err(protocol_error(response)),
+    }
+}
+
+async fn set(c: &mut Connection, key: &str, flags: u32, exptime: u64, value: &[u8]) -> Result<(), CacheError> {
+    let mut request = format!("set {} {} {} {}\r\n", key, flags, exptime, value.len()).into_bytes();
+    request.extend_from_slice(value);
+    request.extend_from_slice(b"\r\n");
+
+    match command(c, &request).await?.as_str() {
+        "STORED" => Ok(()),
+        response => Err(protocol_error(response)),
+    }
+}
+
+/// Items found for the keys, with their flags.
+async fn get(c: &mut Connection, keys: &[String]) -> Result<HashMap<String, (u32, Vec<u8>)>, CacheError> {
+    c.write_all(format!("get {}\r\n", keys.join(" ")).as_bytes()).await?;
+    c.flush().await?;
+
+    let mut items = HashMap::new();
+    loop {
+        let line = read_line(c).await?;
+        if line == "END" {
+            return Ok(items);
+        }
+
+        // VALUE <key> <flags> <bytes>
+        let parts: Vec<&str> = line.split(' ').collect();
+        let (key, flags, length) = match parts.as_slice() {
+            ["VALUE", key, flags, length] => (key.to_string(), flags.parse::<u32>(), length.parse::<usize>()),
+            _ => return Err(protocol_error(&line)),
+        };
+        let (flags, length) = match (flags, length) {
+            (Ok(flags), Ok(length)) => (flags, length),
+            _ => return Err(protocol_error(&line)),
+        };
+
+        let mut value = vec![0u8; length + 2];
+        c.read_exact(&mut value).await?;
+        value.truncate(length);
+        items.insert(key, (flags, value));
+    }
+}
+
+async fn command(c: &mut Connection, request: &[u8]) -> Result<String, CacheError> {
+    c.write_all(request).await?;
+    c.flush().await?;
+    read_line(c).await
+}
+
+async fn read_line(c: &mut Connection) -> Result<String, CacheError> {
+    let mut line = String::new();
+    if c.read_line(&mut line).await? == 0 {
+        return Err(CacheError::FailedToGetKey { reason: "memcached closed the connection".to_string() });
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Metadata as json, followed by a newline and the body. Json never contains a raw newline.
+fn encode_entry(entry: CacheEntry) -> Result<Vec<u8>, CacheError> {
+    let (entry, body) = entry.split_body();
+    let mut value = serde_json::to_vec(&entry)?;
+    value.push(b'\n');
+    value.extend(body);
+    Ok(value)
+}
+
+fn decode_entry(value: &[u8]) -> Result<CacheEntry, CacheError> {
+    let separator = value.iter().position(|v| *v == b'\n')
+        .ok_or_else(|| CacheError::FailedToGetKey { reason: "invalid entry".to_string() })?;
+    let entry: CacheEntry = serde_json::from_slice(&value[..separator])?;
+    Ok(entry.with_body(value[separator + 1..].to_vec()))
+}
+
+fn parse_manifest(manifest: &str) -> Option<(u64, usize)> {
+    let mut parts = manifest.split(' ');
+    let version = parts.next()?.parse().ok()?;
+    let chunk_count = parts.next()?.parse().ok()?;
+    Some((version, chunk_count))
+}
+
+fn expiry_time(ttl: u64) -> u64 {
+    if ttl > MAX_RELATIVE_EXPIRY {
+        unix_now().saturating_add(ttl)
+    } else {
+        ttl
+    }
+}
+
+// keys are limited to 250 bytes without whitespace, object names are neither
+fn item_key(bucket: &str, key: &str) -> String {
+    format!("{}:{}", KEY_PREFIX, sha256_hex(format!("{}:{}", bucket, key).as_bytes()))
+}
+
+fn chunk_key(key: &str, version: u64, index: usize) -> String {
+    format!("{}:{}:{}", key, version, index)
+}
+
+fn protocol_error(response: &str) -> CacheError {
+    CacheError::FailedToGetKey { reason: format!("unexpected memcached response: {}", response) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    use hyper::StatusCode;
+    use tokio::net::TcpListener;
+
+    type Items = Arc<StdMutex<HashMap<String, (u32, Vec<u8>)>>>;
+
+    /// Minimal memcached speaking `set`, `get` and `delete`, returning its address and items.
+    async fn fake_memcached() -> (String, Items) {
+        let items: Items = Arc::new(StdMutex::new(HashMap::new()));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let stored = items.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(BufReader::new(stream), stored.clone()));
+            }
+        });
+
+        (address, items)
+    }
+
+    async fn serve(mut c: Connection, items: Items) -> Result<(), CacheError> {
+        loop {
+            let line = read_line(&mut c).await?;
+            let parts: Vec<&str> = line.split(' ').collect();
+            let response = match parts.as_slice() {
+                ["set", key, flags, _, length] => {
+                    let mut value = vec![0u8; length.parse::<usize>().unwrap() + 2];
+                    c.read_exact(&mut value).await?;
+                    value.truncate(value.len() - 2);
+                    items.lock().unwrap().insert(key.to_string(), (flags.parse().unwrap(), value));
+                    b"STORED\r\n".to_vec()
+                },
+                ["get", keys @ ..] => {
+                    let items = items.lock().unwrap();
+                    let mut response = Vec::new();
+                    for (key, (flags, value)) in keys.iter().filter_map(|v| items.get(*v).map(|item| (v, item))) {
+                        response.extend(format!("VALUE {} {} {}\r\n", key, flags, value.len()).into_bytes());
+                        response.extend(value);
+                        response.extend(b"\r\n");
+                    }
+                    response.extend(b"END\r\n");
+                    response
+                },
+                ["delete", key] => match items.lock().unwrap().remove(*key) {
+                    Some(_) => b"DELETED\r\n".to_vec(),
+                    None => b"NOT_FOUND\r\n".to_vec(),
+                },
+                _ => b"ERROR\r\n".to_vec(),
+            };
+            c.write_all(&response).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_small_values_as_a_single_item() {
+        let (address, items) = fake_memcached().await;
+        let mut c = connect(&address).await.unwrap();
+
+        put_item(&mut c, "key", b"value", 60, 10).await.unwrap();
+
+        assert_eq!(items.lock().unwrap().get("key"), Some(&(FLAG_WHOLE, b"value".to_vec())));
+        assert_eq!(get_item(&mut c, "key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(get_item(&mut c, "missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn splits_large_values_into_chunks() {
+        let (address, items) = fake_memcached().await;
+        let mut c = connect(&address).await.unwrap();
+        let value: Vec<u8> = (0..25).collect();
+
+        put_item(&mut c, "key", &value, 60, 10).await.unwrap();
+
+        let (version, chunk_count) = {
+            let items = items.lock().unwrap();
+            assert_eq!(items.len(), 4);
+            let (flags, manifest) = &items["key"];
+            assert_eq!(*flags, FLAG_CHUNKED);
+            let (version, chunk_count) = parse_manifest(std::str::from_utf8(manifest).unwrap()).unwrap();
+            assert_eq!(items[&chunk_key("key", version, 2)].1, (20..25).collect::<Vec<u8>>());
+            (version, chunk_count)
+        };
+        assert_eq!(chunk_count, 3);
+        assert_eq!(get_item(&mut c, "key").await.unwrap(), Some(value));
+
+        // an entry missing any of its chunks is gone
+        items.lock().unwrap().remove(&chunk_key("key", version, 1));
+        assert_eq!(get_item(&mut c, "key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn deletes_items() {
+        let (address, items) = fake_memcached().await;
+        let mut c = connect(&address).await.unwrap();
+
+        put_item(&mut c, "key", b"value", 60, 10).await.unwrap();
+        delete_item(&mut c, "key").await.unwrap();
+        delete_item(&mut c, "key").await.unwrap();
+
+        assert!(items.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_manifests() {
+        assert_eq!(parse_manifest("42 3"), Some((42, 3)));
+        assert_eq!(parse_manifest("42"), None);
+        assert_eq!(parse_manifest("version 3"), None);
+        assert_eq!(parse_manifest(""), None);
+    }
+
+    #[test]
+    fn encodes_entries_with_their_metadata_in_front_of_the_body() {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), "text/plain".to_string());
+        let entry = CacheEntry::from_body_and_headers(b"line\nline".to_vec(), headers)
+            .with_status(StatusCode::NOT_FOUND);
+
+        let value = encode_entry(entry).unwrap();
+        assert!(value.ends_with(b"}\nline\nline"));
+
+        let result = decode_entry(&value).unwrap().to_get_object_result();
+        assert_eq!(result.status, StatusCode::NOT_FOUND);
+        assert_eq!(result.headers["content-type"], "text/plain");
+        match result.body {
+            crate::origin::object::ObjectBody::Bytes(body) => assert_eq!(body, b"line\nline"),
+            _ => panic!("expected a cached body"),
+        }
+
+        assert!(decode_entry(b"no separator").is_err());
+    }
+}
@@ -1,14 +1,17 @@
 use actix_derive::Message;
 use serde::{Serialize, Deserialize};
 use custom_error::custom_error;
-use crate::gcs::GetObjectResult;
+use crate::origin::object::{GetObjectResult, ObjectBody};
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::StatusCode;
 
 custom_error! {pub CacheError
     FailedToCreateCacheClient {reason: String} = "failed to create cache client: {}",
     SerdeError {source: serde_json::Error} = "failed to serialize/deserialize entry: {source}",
     FailedToGetKey {reason: String} = "failed to get key: {reason}",
-    FailedToSendMessage {source: actix::MailboxError} = "failed to send message: {source}"
+    FailedToSendMessage {source: actix::MailboxError} = "failed to send message: {source}",
+    FailedToAccessFile {source: std::io::Error} = "failed to access cache file: {source}"
 }
 
 #[derive(Message, Clone)]
@@ -16,20 +19,51 @@ custom_error! {pub CacheError
 pub struct PutCacheEntry {
     pub bucket: String,
     pub key: String,
-    pub entry: CacheEntry
+    pub generation: Option<u64>,
+    pub entry: CacheEntry,
+    // overrides the ttl of the cache, in seconds
+    pub ttl: Option<u64>
 }
 
 #[derive(Message, Clone)]
 #[rtype(result = "Result<CacheEntry, CacheError>")]
 pub struct GetCacheEntry {
+    pub bucket: String,
+    pub key: String,
+    pub generation: Option<u64>
+}
+
+#[derive(Message, Clone)]
+#[rtype(result = "Result<(), CacheError>")]
+pub struct DeleteCacheEntry {
     pub bucket: String,
     pub key: String
 }
 
+/// Key of a specific object generation, or of the live object if no generation is given.
+pub fn entry_key(key: &str, generation: Option<u64>) -> String {
+    match generation {
+        Some(generation) => format!("{}#{}", key, generation),
+        None => key.to_string(),
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CacheEntry {
     body: Vec<u8>,
-    headers: HashMap<String, String>
+    headers: HashMap<String, String>,
+    // status of the cached response, not found results are cached as well
+    #[serde(default = "default_status")]
+    status: u16,
+    // unix timestamp until which the entry is served without contacting the origin
+    #[serde(default)]
+    fresh_until: u64,
+    // seconds after `fresh_until` during which the entry is served while it is refreshed in the background
+    #[serde(default)]
+    stale_while_revalidate: u64,
+    // seconds after `fresh_until` during which the entry is served if the origin fails
+    #[serde(default)]
+    stale_if_error: u64,
 }
 
 impl CacheEntry {
@@ -38,13 +72,82 @@ impl CacheEntry {
         CacheEntry {
             body,
             headers,
+            status: StatusCode::OK.as_u16(),
+            fresh_until: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+        }
+    }
+
+    /// Marker for a missing object. The not found page served for it is cached as the object it is.
+    pub fn not_found() -> Self {
+        Self::from_body_and_headers(Vec::new(), HashMap::new()).with_status(StatusCode::NOT_FOUND)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status == StatusCode::NOT_FOUND.as_u16()
+    }
+
+    pub fn with_status(self, status: StatusCode) -> Self {
+        CacheEntry {
+            status: status.as_u16(),
+            ..self
+        }
+    }
+
+    /// Splits off the body, for caches which store it separately from the rest of the entry.
+    pub fn split_body(self) -> (Self, Vec<u8>) {
+        let body = self.body;
+        (CacheEntry { body: Vec::new(), ..self }, body)
+    }
+
+    pub fn with_body(self, body: Vec<u8>) -> Self {
+        CacheEntry {
+            body,
+            ..self
+        }
+    }
+
+    pub fn with_lifetime(self, ttl: u64, stale_while_revalidate: u64, stale_if_error: u64) -> Self {
+        CacheEntry {
+            fresh_until: unix_now().saturating_add(ttl),
+            stale_while_revalidate,
+            stale_if_error,
+            ..self
         }
     }
 
+    /// How long the cache has to keep the entry, including the time it may be served stale.
+    pub fn storage_ttl(&self) -> u64 {
+        self.fresh_until.saturating_sub(unix_now())
+            .saturating_add(self.stale_while_revalidate.max(self.stale_if_error))
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        unix_now() < self.fresh_until
+    }
+
+    pub fn is_within_stale_while_revalidate(&self) -> bool {
+        unix_now() < self.fresh_until.saturating_add(self.stale_while_revalidate)
+    }
+
+    pub fn is_within_stale_if_error(&self) -> bool {
+        unix_now() < self.fresh_until.saturating_add(self.stale_if_error)
+    }
+
     pub fn to_get_object_result(self) -> GetObjectResult {
         GetObjectResult {
-            body: self.body,
+            status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK),
+            body: ObjectBody::Bytes(self.body),
             headers: self.headers,
         }
     }
 }
+
+fn default_status() -> u16 {
+    StatusCode::OK.as_u16()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0)
+}
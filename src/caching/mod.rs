@@ -1,4 +1,8 @@
 pub mod caching;
 pub mod messages;
 pub mod local;
-pub mod redis;
\ No newline at end of file
+pub mod redis;
+pub mod cache_control;
+pub mod tiered;
+pub mod disk;
+pub mod memcached;
\ No newline at end of file
@@ -1,5 +1,5 @@
 use actix::{Context, Handler, Actor, ResponseFuture};
-use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, CacheError};
+use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheError, entry_key};
 use redis_async::resp_array;
 use tokio::sync::Mutex;
 use std::sync::Arc;
@@ -16,6 +16,10 @@ lazy_static! {
         "redis_cache_put",
         "redis cache put operations"
     ).unwrap();
+    static ref REDIS_CACHE_DELETE: Counter = register_counter!(
+        "redis_cache_delete",
+        "redis cache delete operations"
+    ).unwrap();
 }
 
 pub struct RedisCache {
@@ -52,14 +56,14 @@ impl Handler<PutCacheEntry> for RedisCache {
     fn handle(&mut self, msg: PutCacheEntry, _: &mut Context<Self>) -> Self::Result {
         let client = self.client.clone();
         let msg = msg.clone();
-        let ttl = self.ttl.clone();
+        let ttl = msg.ttl.unwrap_or(self.ttl);
 
         REDIS_CACHE_PUT.inc();
 
         Box::pin(async move {
             let client = client.lock().await;
 
-            let key = format!("{}:{}:{}", KEY_PREFIX, msg.bucket, msg.key);
+            let key = format!("{}:{}:{}", KEY_PREFIX, msg.bucket, entry_key(&msg.key, msg.generation));
             let entry = serde_json::to_string(&msg.entry)?;
             client.send_and_forget(resp_array!["SET", &key, entry]);
             client.send_and_forget(resp_array!["EXPIRE", &key, format!("{}", &ttl)]);
@@ -81,7 +85,7 @@ impl Handler<GetCacheEntry> for RedisCache {
         Box::pin(async move {
             let client = client.lock().await;
 
-            let key = format!("{}:{}:{}", KEY_PREFIX, msg.bucket, msg.key);
+            let key = format!("{}:{}:{}", KEY_PREFIX, msg.bucket, entry_key(&msg.key, msg.generation));
             let entry_str = client.send::<String>(resp_array!["GET", key]).await
                 .map_err(|err| CacheError::FailedToGetKey { reason: format!("{}", err) })?;
             let entry = serde_json::from_str(&entry_str)?;
@@ -90,3 +94,23 @@ impl Handler<GetCacheEntry> for RedisCache {
         })
     }
 }
+
+impl Handler<DeleteCacheEntry> for RedisCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: DeleteCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let client = self.client.clone();
+
+        REDIS_CACHE_DELETE.inc();
+
+        Box::pin(async move {
+            let client = client.lock().await;
+
+            let key = format!("{}:{}:{}", KEY_PREFIX, msg.bucket, msg.key);
+            client.send::<i64>(resp_array!["DEL", key]).await
+                .map_err(|err| CacheError::FailedToGetKey { reason: format!("{}", err) })?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,140 @@
+use actix::{Context, Handler, Actor, ResponseFuture};
+use prometheus::{CounterVec, register_counter_vec};
+use crate::caching::caching::CacheInstance;
+use crate::caching::messages::{CacheEntry, GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheError};
+
+lazy_static! {
+    static ref TIERED_CACHE_HITS: CounterVec = register_counter_vec!(
+        "tiered_cache_hits",
+        "tiered cache hits by tier",
+        &["cache", "tier"]
+    ).unwrap();
+    static ref TIERED_CACHE_MISSES: CounterVec = register_counter_vec!(
+        "tiered_cache_misses",
+        "tiered cache lookups not found in any tier",
+        &["cache"]
+    ).unwrap();
+}
+
+/// Composes named caches, fastest first. Fresh entries are preferred over stale ones in upper tiers,
+/// entries found in a lower tier are promoted to the tiers above it. Puts and deletes go to every tier.
+pub struct TieredCache {
+    name: String,
+    tiers: Vec<(String, CacheInstance)>,
+}
+
+impl TieredCache {
+    pub fn new(name: String, tiers: Vec<(String, CacheInstance)>) -> Self {
+        Self {
+            name,
+            tiers,
+        }
+    }
+}
+
+impl Actor for TieredCache {
+    type Context = Context<Self>;
+}
+
+impl Handler<PutCacheEntry> for TieredCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: PutCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let tiers = self.tiers.clone();
+
+        Box::pin(async move {
+            let mut result = Ok(());
+
+            for (name, tier) in &tiers {
+                if let Err(err) = tier.send_put_message(msg.clone()).await {
+                    warn!("failed to put entry to cache tier {}: {}", name, err);
+                    result = result.and(Err(err));
+                }
+            }
+
+            result
+        })
+    }
+}
+
+impl Handler<GetCacheEntry> for TieredCache {
+    type Result = ResponseFuture<Result<CacheEntry, CacheError>>;
+
+    fn handle(&mut self, msg: GetCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let name = self.name.clone();
+        let tiers = self.tiers.clone();
+
+        Box::pin(async move {
+            // a stale entry is only used if no lower tier has a fresh one
+            let mut found: Option<(usize, CacheEntry)> = None;
+
+            for (i, (_, tier)) in tiers.iter().enumerate() {
+                let entry = match tier.send_get_message(msg.clone()).await {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+
+                let is_fresh = entry.is_fresh();
+                if is_fresh || found.is_none() {
+                    found = Some((i, entry));
+                }
+                if is_fresh {
+                    break;
+                }
+            }
+
+            let (i, entry) = match found {
+                Some(v) => v,
+                None => {
+                    TIERED_CACHE_MISSES.with_label_values(&[&name]).inc();
+
+                    return Err(CacheError::FailedToGetKey {
+                        reason: "Key not present in any tier".to_string()
+                    });
+                }
+            };
+
+            TIERED_CACHE_HITS.with_label_values(&[&name, &tiers[i].0]).inc();
+
+            let ttl = entry.storage_ttl();
+            if ttl > 0 {
+                for (upper_name, upper) in &tiers[..i] {
+                    let put_cache_message = PutCacheEntry {
+                        bucket: msg.bucket.clone(),
+                        key: msg.key.clone(),
+                        generation: msg.generation,
+                        entry: entry.clone(),
+                        ttl: Some(ttl),
+                    };
+
+                    if let Err(err) = upper.send_put_message(put_cache_message).await {
+                        warn!("failed to promote entry to cache tier {}: {}", upper_name, err);
+                    }
+                }
+            }
+
+            Ok(entry)
+        })
+    }
+}
+
+impl Handler<DeleteCacheEntry> for TieredCache {
+    type Result = ResponseFuture<Result<(), CacheError>>;
+
+    fn handle(&mut self, msg: DeleteCacheEntry, _: &mut Context<Self>) -> Self::Result {
+        let tiers = self.tiers.clone();
+
+        Box::pin(async move {
+            let mut result = Ok(());
+
+            for (name, tier) in &tiers {
+                if let Err(err) = tier.send_delete_message(msg.clone()).await {
+                    warn!("failed to delete entry from cache tier {}: {}", name, err);
+                    result = result.and(Err(err));
+                }
+            }
+
+            result
+        })
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use futures::channel::oneshot;
+use prometheus::{Counter, register_counter};
+use crate::caching::messages::CacheEntry;
+use crate::origin::object::{OriginError, GetObjectResult, ObjectBody};
+use hyper::StatusCode;
+
+lazy_static! {
+    static ref COALESCED_REQUESTS_COUNTER: Counter = register_counter!(
+        "coalesced_requests",
+        "cache misses served by an origin request which was already in flight"
+    ).unwrap();
+}
+
+type Waiters = Vec<oneshot::Sender<FetchOutcome>>;
+
+/// Outcome of an origin request, handed to the requests which waited for it.
+#[derive(Clone)]
+pub enum FetchOutcome {
+    Entry(CacheEntry),
+    Error(Arc<OriginError>),
+    // streamed or partial responses can not be shared, waiters fetch the object themselves
+    NotShared,
+}
+
+impl FetchOutcome {
+
+    pub fn from_result(result: &Result<GetObjectResult, Arc<OriginError>>) -> Self {
+        match result {
+            Ok(GetObjectResult { status, body: ObjectBody::Bytes(body), headers })
+                if *status == StatusCode::OK || *status == StatusCode::NOT_FOUND =>
+                FetchOutcome::Entry(CacheEntry::from_body_and_headers(body.clone(), headers.clone()).with_status(*status)),
+            Ok(_) => FetchOutcome::NotShared,
+            Err(err) => FetchOutcome::Error(err.clone()),
+        }
+    }
+}
+
+pub enum Flight {
+    Leader(FlightGuard),
+    Follower(oneshot::Receiver<FetchOutcome>),
+}
+
+/// Deduplicates concurrent origin requests for the same key: the first request fetches the object,
+/// the others wait for its outcome.
+#[derive(Default)]
+pub struct RequestCoalescing {
+    in_flight: Arc<Mutex<HashMap<String, Waiters>>>,
+}
+
+impl RequestCoalescing {
+
+    pub fn join(&self, key: String) -> Flight {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if let Some(waiters) = in_flight.get_mut(&key) {
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(sender);
+            return Flight::Follower(receiver);
+        }
+
+        in_flight.insert(key.clone(), Vec::new());
+
+        Flight::Leader(FlightGuard {
+            in_flight: self.in_flight.clone(),
+            key: Some(key),
+        })
+    }
+
+    /// Fetches an object once for all concurrent requests with the same key. Requests which can not use
+    /// the outcome of the request in flight fetch the object themselves.
+    pub async fn fetch<F, Fut>(&self, key: String, fetch: F) -> Result<GetObjectResult, Arc<OriginError>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<GetObjectResult, Arc<OriginError>>>,
+    {
+        match self.join(key) {
+            Flight::Leader(guard) => {
+                let result = fetch().await;
+                guard.complete(FetchOutcome::from_result(&result));
+                result
+            },
+            Flight::Follower(receiver) => match receiver.await.unwrap_or(FetchOutcome::NotShared) {
+                FetchOutcome::Entry(entry) => Ok(entry.to_get_object_result()),
+                FetchOutcome::Error(err) => Err(err),
+                FetchOutcome::NotShared => fetch().await,
+            }
+        }
+    }
+}
+
+/// Held by the request fetching the object. If it is dropped without completing (e.g. the client
+/// disconnected) the waiters are released and fetch the object themselves.
+pub struct FlightGuard {
+    in_flight: Arc<Mutex<HashMap<String, Waiters>>>,
+    key: Option<String>,
+}
+
+impl FlightGuard {
+
+    pub fn complete(mut self, outcome: FetchOutcome) {
+        let shared = !matches!(outcome, FetchOutcome::NotShared);
+
+        for waiter in self.take_waiters() {
+            if waiter.send(outcome.clone()).is_ok() && shared {
+                COALESCED_REQUESTS_COUNTER.inc();
+            }
+        }
+    }
+
+    fn take_waiters(&mut self) -> Waiters {
+        match self.key.take() {
+            Some(key) => self.in_flight.lock().unwrap().remove(&key).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for FlightGuard {
+
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use futures::future::join_all;
+    use futures::stream::{self, StreamExt};
+
+    const CONCURRENT_REQUESTS: usize = 5;
+
+    /// Runs concurrent fetches of the same key, returning their results and the number of origin requests.
+    async fn fetch_concurrently<F>(result: F) -> (Vec<Result<GetObjectResult, Arc<OriginError>>>, usize)
+    where
+        F: Fn() -> Result<GetObjectResult, Arc<OriginError>>,
+    {
+        let coalescing = RequestCoalescing::default();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            result()
+        };
+
+        let results = join_all((0..CONCURRENT_REQUESTS).map(|_| coalescing.fetch("bucket/key".to_string(), fetch))).await;
+        (results, fetches.load(Ordering::SeqCst))
+    }
+
+    fn object(body: ObjectBody) -> Result<GetObjectResult, Arc<OriginError>> {
+        Ok(GetObjectResult {
+            status: StatusCode::OK,
+            body,
+            headers: HashMap::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn shares_the_leaders_entry() {
+        let (results, fetches) = fetch_concurrently(|| object(ObjectBody::Bytes(b"object".to_vec()))).await;
+
+        assert_eq!(fetches, 1);
+        for result in results {
+            match result.unwrap().body {
+                ObjectBody::Bytes(body) => assert_eq!(body, b"object"),
+                ObjectBody::Stream(_) => panic!("expected a shared body"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_the_leaders_error() {
+        let (results, fetches) = fetch_concurrently(|| Err(Arc::new(OriginError::Timeout))).await;
+
+        assert_eq!(fetches, 1);
+        for result in results {
+            assert!(matches!(result.map(|_| ()).unwrap_err().as_ref(), OriginError::Timeout));
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_fetch_responses_which_are_not_shared() {
+        let (results, fetches) = fetch_concurrently(|| object(ObjectBody::Stream(stream::empty().boxed()))).await;
+
+        assert_eq!(fetches, CONCURRENT_REQUESTS);
+        assert!(results.iter().all(|v| v.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn dropped_leader_releases_followers() {
+        let coalescing = RequestCoalescing::default();
+
+        let leader = match coalescing.join("bucket/key".to_string()) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(_) => panic!("expected the first request to lead"),
+        };
+        let follower = match coalescing.join("bucket/key".to_string()) {
+            Flight::Follower(receiver) => receiver,
+            Flight::Leader(_) => panic!("expected the second request to follow"),
+        };
+
+        drop(leader);
+
+        assert!(follower.await.is_err());
+        assert!(matches!(coalescing.join("bucket/key".to_string()), Flight::Leader(_)));
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use httpdate::parse_http_date;
+
+// headers that are kept in a 304 response, everything else describes the (omitted) body
+const NOT_MODIFIED_HEADERS: &[&str] = &["cache-control", "content-location", "date", "etag", "expires", "last-modified", "vary"];
+
+/// Evaluates `If-None-Match` and `If-Modified-Since` against object headers. `If-Modified-Since` is only
+/// considered when `If-None-Match` is not present.
+pub fn is_not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>, headers: &HashMap<String, String>) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        let etag = match headers.get("etag") {
+            Some(v) => v,
+            None => return false,
+        };
+
+        return if_none_match.split(',')
+            .map(|v| v.trim())
+            .any(|v| v == "*" || weak_etag(v) == weak_etag(etag));
+    }
+
+    if let Some(if_modified_since) = if_modified_since {
+        let last_modified = match headers.get("last-modified").and_then(|v| parse_http_date(v).ok()) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        return match parse_http_date(if_modified_since) {
+            Ok(v) => last_modified <= v,
+            Err(_) => false,
+        };
+    }
+
+    false
+}
+
+/// Whether the validator of an `If-Range` header still matches the object, in which case the requested
+/// range may be served. Entity tags are compared strongly, dates have to equal the modification date.
+pub fn if_range_matches(if_range: &str, headers: &HashMap<String, String>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/") && headers.get("etag").map(|v| v == if_range).unwrap_or(false);
+    }
+
+    match (parse_http_date(if_range), headers.get("last-modified").and_then(|v| parse_http_date(v).ok())) {
+        (Ok(date), Some(last_modified)) => date == last_modified,
+        _ => false,
+    }
+}
+
+pub fn not_modified_headers(headers: HashMap<String, String>) -> HashMap<String, String> {
+    headers.into_iter()
+        .filter(|(k, _)| NOT_MODIFIED_HEADERS.contains(&k.as_str()))
+        .collect()
+}
+
+fn weak_etag(etag: &str) -> &str {
+    etag.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    #[test]
+    fn matches_etags_weakly() {
+        let strong = headers(&[("etag", "\"abc\"")]);
+        let weak = headers(&[("etag", "W/\"abc\"")]);
+
+        assert!(is_not_modified(Some("\"abc\""), None, &strong));
+        assert!(is_not_modified(Some("W/\"abc\""), None, &strong));
+        assert!(is_not_modified(Some("\"abc\""), None, &weak));
+        assert!(is_not_modified(Some("W/\"abc\""), None, &weak));
+        assert!(!is_not_modified(Some("\"abd\""), None, &weak));
+    }
+
+    #[test]
+    fn matches_any_etag_in_list() {
+        let object = headers(&[("etag", "\"b\"")]);
+
+        assert!(is_not_modified(Some("\"a\", W/\"b\""), None, &object));
+        assert!(is_not_modified(Some("*"), None, &object));
+        assert!(!is_not_modified(Some("\"a\", \"c\""), None, &object));
+        assert!(!is_not_modified(Some("*"), None, &HashMap::new()));
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let object = headers(&[("last-modified", "Sun, 13 Sep 2020 12:26:40 GMT")]);
+
+        assert!(is_not_modified(None, Some("Sun, 13 Sep 2020 12:26:40 GMT"), &object));
+        assert!(is_not_modified(None, Some("Mon, 14 Sep 2020 00:00:00 GMT"), &object));
+        assert!(!is_not_modified(None, Some("Sat, 12 Sep 2020 00:00:00 GMT"), &object));
+        assert!(!is_not_modified(None, Some("yesterday"), &object));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let object = headers(&[("etag", "\"a\""), ("last-modified", "Sun, 13 Sep 2020 12:26:40 GMT")]);
+
+        assert!(!is_not_modified(Some("\"b\""), Some("Mon, 14 Sep 2020 00:00:00 GMT"), &object));
+    }
+
+    #[test]
+    fn matches_if_range_validators_strongly() {
+        let object = headers(&[("etag", "\"a\""), ("last-modified", "Sun, 13 Sep 2020 12:26:40 GMT")]);
+        let weak = headers(&[("etag", "W/\"a\"")]);
+
+        assert!(if_range_matches("\"a\"", &object));
+        assert!(!if_range_matches("\"b\"", &object));
+        assert!(!if_range_matches("W/\"a\"", &object));
+        assert!(!if_range_matches("\"a\"", &weak));
+        assert!(if_range_matches("Sun, 13 Sep 2020 12:26:40 GMT", &object));
+        assert!(!if_range_matches("Mon, 14 Sep 2020 00:00:00 GMT", &object));
+        assert!(!if_range_matches("Sun, 13 Sep 2020 12:26:40 GMT", &weak));
+    }
+
+    #[test]
+    fn keeps_only_metadata_headers() {
+        let object = headers(&[("etag", "\"a\""), ("content-length", "10"), ("content-type", "text/plain")]);
+
+        assert_eq!(not_modified_headers(object), headers(&[("etag", "\"a\"")]));
+    }
+}
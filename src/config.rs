@@ -6,7 +6,17 @@ use std::io::{Read, ErrorKind};
 use std::io::Error as IOError;
 use std::convert::TryInto;
 use toml::de::Error as TomlError;
-use std::{net::IpAddr, collections::HashMap};
+use std::{net::IpAddr, collections::HashMap, time::Duration};
+use crate::origin::object::decode_object_name;
+
+const DEFAULT_MAX_CACHED_OBJECT_SIZE: u64 = 8 * 1024 * 1024;
+const DEFAULT_CACHE_TTL: u64 = 3600;
+const DEFAULT_NOT_FOUND_TTL: u64 = 60;
+const DEFAULT_IMMUTABLE_TTL: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 100;
+const DEFAULT_RETRY_MAX_DELAY_MS: u64 = 2000;
+const DEFAULT_CIRCUIT_BREAKER_RESET_SECONDS: u64 = 30;
 
 custom_error! {pub LoadConfigError
     FailedToRead{source: IOError} = "failed to read config file: {source}",
@@ -21,26 +31,75 @@ impl From<LoadConfigError> for IOError {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub service_account_key: Option<String>,
-    pub service_account_key_file: Option<String>,
+    #[serde(flatten)]
+    pub default_credentials: CredentialsConfiguration,
+    pub storage_endpoint: Option<String>,
+    pub connection_pool_size: Option<usize>,
+    pub request_timeout_seconds: Option<u64>,
     pub bind_address: Option<String>,
     pub port: Option<u16>,
     pub metrics: Option<bool>,
     pub metrics_endpoint: Option<String>,
+    pub credentials: Option<HashMap<String, CredentialsConfiguration>>,
     pub caching: Option<HashMap<String, Caching>>,
     pub buckets: Option<HashMap<String, BucketConfiguration>>,
     pub rate_limiting_groups: Option<HashMap<String, RateLimitingConfiguration>>
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct CredentialsConfiguration {
+    pub authentication: Option<String>,
+    pub scope: Option<String>,
+
+    // service account
+    pub service_account_key: Option<String>,
+    pub service_account_key_file: Option<String>,
+
+    // application default credentials
+    pub credentials_file: Option<String>,
+
+    // metadata server (also used by application default credentials when no credentials file is found)
+    pub metadata_server_url: Option<String>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct BucketConfiguration {
     pub host: String,
+    #[serde(rename="origin")]
+    pub origin_type: Option<String>,
     pub bucket: Option<String>,
+    pub prefix: Option<String>,
+    pub strip_prefix: Option<String>,
     pub index: Option<String>,
+    pub directory_listing: Option<String>,
+    pub write_token: Option<String>,
+    pub delete_token: Option<String>,
     pub not_found: Option<String>,
     pub cache_name: Option<String>,
+    pub credentials: Option<String>,
     pub headers: Option<HashMap<String, String>>,
-    pub rate_limiter_name: Option<String>
+    pub rate_limiter_name: Option<String>,
+
+    // retries
+    pub retry_max_attempts: Option<u32>,
+    pub retry_base_delay_ms: Option<u64>,
+    pub retry_max_delay_ms: Option<u64>,
+
+    // circuit breaker, disabled unless the threshold is set
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_reset_seconds: Option<u64>,
+
+    // s3 origin
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+
+    // filesystem origin
+    pub directory: Option<String>,
+
+    // http origin
+    pub upstream: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -48,13 +107,35 @@ pub struct Caching {
     #[serde(rename="type")]
     pub caching_type: Option<String>,
     pub ttl: Option<u64>,
+    // bounds for ttls derived from the Cache-Control or Expires headers of objects
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+    pub max_object_size: Option<u64>,
+    pub immutable_ttl: Option<u64>,
+    // ttl of not found markers, 0 disables caching them
+    pub not_found_ttl: Option<u64>,
+    // seconds an expired entry is served while it is refreshed in the background
+    pub stale_while_revalidate: Option<u64>,
+    // seconds an expired entry is served when the origin fails
+    pub stale_if_error: Option<u64>,
 
     // local cache
     pub capacity: Option<usize>,
 
-    // redis
+    // redis and memcached
     pub host: Option<String>,
     pub port: Option<u16>,
+
+    // memcached, larger entries are split into several items
+    pub max_item_size: Option<usize>,
+
+    // disk
+    pub directory: Option<String>,
+    // size budget of all cached bodies in bytes
+    pub max_size: Option<u64>,
+
+    // tiered, names of the caches to compose, fastest first
+    pub tiers: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -67,13 +148,30 @@ pub struct RateLimitingConfiguration {
 
 impl Config {
 
-    pub fn bucket_configuration_by_host(&self, host: &str) -> Option<&BucketConfiguration> {
+    pub fn bucket_configuration_by_host(&self, host: &str) -> Option<(&String, &BucketConfiguration)> {
         let buckets = match &self.buckets {
             Some(v) => v,
             None => return None
         };
 
-        buckets.values().find(|v| v.host  == host)
+        buckets.iter().find(|v| v.1.host  == host)
+    }
+
+    /// Credentials with the given name, or the top-level ones if no name is given.
+    pub fn credentials_by_name(&self, name: Option<&str>) -> Option<&CredentialsConfiguration> {
+        match name {
+            Some(name) => self.credentials.as_ref().and_then(|v| v.get(name)),
+            None => Some(&self.default_credentials),
+        }
+    }
+
+    /// Timeout for connecting to origins and receiving their response headers, none by default.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_seconds.map(Duration::from_secs)
+    }
+
+    pub fn caching_configuration_by_name(&self, name: &str) -> Option<&Caching> {
+        self.caching.as_ref().and_then(|v| v.get(name))
     }
 
     pub fn ip_addr(&self) -> Option<IpAddr> {
@@ -90,6 +188,137 @@ impl Config {
     }
 }
 
+impl CredentialsConfiguration {
+
+    /// One of `service_account`, `application_default`, `metadata_server` or `anonymous`.
+    pub fn authentication_type(&self) -> &str {
+        self.authentication.as_deref().unwrap_or("service_account")
+    }
+
+    /// OAuth scope requested for cloud storage: `read_only`, `read_write` or `full_control`. Defaults to
+    /// `read_write` for credentials used by buckets accepting writes, and `read_only` otherwise.
+    pub fn scope(&self, writable: bool) -> String {
+        let default = if writable { "read_write" } else { "read_only" };
+        format!("https://www.googleapis.com/auth/devstorage.{}", self.scope.as_deref().unwrap_or(default))
+    }
+}
+
+impl BucketConfiguration {
+
+    pub fn origin_type(&self) -> &str {
+        self.origin_type.as_deref().unwrap_or("gcs")
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.write_token.is_some() || self.delete_token.is_some()
+    }
+
+    pub fn index(&self) -> &str {
+        self.index.as_deref().unwrap_or("index.html")
+    }
+
+    pub fn not_found_object_name(&self) -> String {
+        format!("{}{}", self.prefix(), self.not_found.as_deref().unwrap_or("404.html"))
+    }
+
+    /// Prefix of all objects served for this host, always ending with a slash unless empty.
+    pub fn prefix(&self) -> String {
+        match self.prefix.as_deref().map(|v| v.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{}/", prefix),
+            _ => String::new(),
+        }
+    }
+
+    /// Maps a request path to the name of the object in the bucket: `strip_prefix` is removed from the
+    /// path if it matches whole path segments, and `prefix` is prepended. Paths with `.` or `..` segments,
+    /// plain or percent-encoded, are rejected as they could address objects outside of the prefix.
+    pub fn object_name(&self, path: &str) -> Option<String> {
+        if decode_object_name(path).is_err() {
+            return None;
+        }
+
+        let path = match self.strip_prefix.as_deref().map(|v| v.trim_end_matches('/')) {
+            Some(strip_prefix) if !strip_prefix.is_empty() => match path.strip_prefix(strip_prefix) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                _ => path,
+            },
+            _ => path,
+        };
+
+        Some(format!("{}{}", self.prefix(), path.trim_start_matches('/')))
+    }
+
+    /// Origin the objects of this host are read from, such as `gcs:assets` or `http:https://example.com`,
+    /// so hosts sharing a cache only share entries of the same objects.
+    pub fn origin_namespace(&self, name: &str) -> String {
+        let bucket = self.bucket.as_deref().unwrap_or(name);
+        match self.origin_type() {
+            "s3" => format!("s3:{}/{}", self.endpoint.as_deref().or(self.region.as_deref()).unwrap_or(""), bucket),
+            "filesystem" => format!("filesystem:{}", self.directory.as_deref().unwrap_or("")),
+            "http" => format!("http:{}", self.upstream.as_deref().unwrap_or("")),
+            origin_type => format!("{}:{}", origin_type, bucket),
+        }
+    }
+
+    /// Namespace of cached entries of this host. Hosts generating directory listings or serving their own
+    /// not found page cache them apart from other hosts of the same origin, under their configuration entry.
+    pub fn cache_namespace(&self, name: &str) -> String {
+        let origin_namespace = self.origin_namespace(name);
+        if self.directory_listing.is_some() || self.not_found.is_some() {
+            format!("{}#{}", origin_namespace, name)
+        } else {
+            origin_namespace
+        }
+    }
+
+    /// Local files are not retried by default: their failures are not transient.
+    pub fn retry_max_attempts(&self) -> u32 {
+        let default = if self.origin_type() == "filesystem" { 1 } else { DEFAULT_RETRY_MAX_ATTEMPTS };
+        self.retry_max_attempts.unwrap_or(default)
+    }
+
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_ms.unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS))
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_ms.unwrap_or(DEFAULT_RETRY_MAX_DELAY_MS))
+    }
+
+    pub fn circuit_breaker_reset_timeout(&self) -> Duration {
+        Duration::from_secs(self.circuit_breaker_reset_seconds.unwrap_or(DEFAULT_CIRCUIT_BREAKER_RESET_SECONDS))
+    }
+}
+
+impl Caching {
+
+    /// Objects larger than this are streamed to the client without being cached.
+    pub fn max_object_size(&self) -> u64 {
+        self.max_object_size.unwrap_or(DEFAULT_MAX_CACHED_OBJECT_SIZE)
+    }
+
+    pub fn is_tiered(&self) -> bool {
+        self.caching_type.as_deref() == Some("tiered")
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl.unwrap_or(DEFAULT_CACHE_TTL)
+    }
+
+    pub fn clamp_ttl(&self, ttl: u64) -> u64 {
+        ttl.max(self.min_ttl.unwrap_or(0)).min(self.max_ttl.unwrap_or(u64::MAX))
+    }
+
+    pub fn not_found_ttl(&self) -> u64 {
+        self.not_found_ttl.unwrap_or(DEFAULT_NOT_FOUND_TTL)
+    }
+
+    /// Ttl for objects which never change, like specific object generations.
+    pub fn immutable_ttl(&self) -> u64 {
+        self.immutable_ttl.unwrap_or(DEFAULT_IMMUTABLE_TTL)
+    }
+}
+
 fn get_config_file_name() -> String {
     var("CONFIG_FILE").unwrap_or("config.toml".into())
 }
@@ -102,4 +331,53 @@ pub fn load_config() -> Result<Config, LoadConfigError> {
         .map_err(|source| LoadConfigError::FailedToRead { source })?;
 
     toml::from_str(&config_str).map_err(|source| LoadConfigError::FailedToDeserialize { source })
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(config: &str) -> BucketConfiguration {
+        toml::from_str(&format!("host = \"example.com\"\n{}", config)).unwrap()
+    }
+
+    #[test]
+    fn prepends_prefix() {
+        assert_eq!(bucket("").object_name("/a/b.txt").unwrap(), "a/b.txt");
+        assert_eq!(bucket("prefix = \"/docs/\"").object_name("/a/b.txt").unwrap(), "docs/a/b.txt");
+        assert_eq!(bucket("prefix = \"docs\"").object_name("/").unwrap(), "docs/");
+    }
+
+    #[test]
+    fn strips_prefix_at_segment_boundaries() {
+        let bucket = bucket("prefix = \"docs\"\nstrip_prefix = \"/site/\"");
+
+        assert_eq!(bucket.object_name("/site/a.txt").unwrap(), "docs/a.txt");
+        assert_eq!(bucket.object_name("/site").unwrap(), "docs/");
+        assert_eq!(bucket.object_name("/sitemap.xml").unwrap(), "docs/sitemap.xml");
+        assert_eq!(bucket.object_name("/other/site/a.txt").unwrap(), "docs/other/site/a.txt");
+    }
+
+    #[test]
+    fn rejects_invalid_object_names() {
+        let bucket = bucket("prefix = \"docs\"\nstrip_prefix = \"/site\"");
+
+        // names are checked before the prefix is stripped, which could otherwise remove a dot segment
+        assert_eq!(bucket.object_name("/site/%2e%2e/other/a"), None);
+    }
+
+    #[test]
+    fn namespaces_cache_entries_by_origin() {
+        assert_eq!(bucket("bucket = \"assets\"").cache_namespace("site"), "gcs:assets");
+        assert_eq!(bucket("").cache_namespace("assets"), "gcs:assets");
+        assert_eq!(bucket("origin = \"s3\"\nbucket = \"assets\"").cache_namespace("site"), "s3:/assets");
+        assert_eq!(
+            bucket("origin = \"s3\"\nbucket = \"assets\"\nendpoint = \"http://minio:9000\"").cache_namespace("site"),
+            "s3:http://minio:9000/assets"
+        );
+        assert_eq!(bucket("origin = \"http\"\nupstream = \"http://app\"").cache_namespace("site"), "http:http://app");
+        assert_eq!(
+            bucket("bucket = \"assets\"\ndirectory_listing = \"html\"").cache_namespace("site"),
+            "gcs:assets#site"
+        );
+    }
+}
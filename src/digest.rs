@@ -0,0 +1,22 @@
+use openssl::sha::sha256;
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+/// Hex encoded sha256 digest, also used to turn cache keys into short names safe for any storage.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&sha256(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_sha256_digests() {
+        assert_eq!(hex(&[0x00, 0x0f, 0xab]), "000fab");
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
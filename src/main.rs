@@ -5,24 +5,38 @@ extern crate custom_error;
 extern crate ttl_cache;
 
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Request, Body, Response, Server, Method, Error, StatusCode, header::{HeaderValue, HeaderName}};
+use hyper::{Request, Body, Response, Server, Method, Error, StatusCode, header::{HeaderValue, HeaderName, CACHE_CONTROL, RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE, IF_RANGE, ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE}};
 use crate::config::{load_config, Config};
-use crate::gcs::{GoogleCloudStorageClient, GCSClientError};
-use std::fs;
-use std::{sync::Arc, env::var};
-use gcs::GetObjectResult;
-use crate::caching::messages::{GetCacheEntry, PutCacheEntry, CacheEntry};
-use crate::caching::caching::Caching;
+use crate::origin::object::{is_transient_status, OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ObjectBody};
+use crate::origin::origin::{Origin, Origins};
+use std::sync::Arc;
+use crate::caching::messages::{GetCacheEntry, PutCacheEntry, DeleteCacheEntry, CacheEntry, entry_key};
+use crate::caching::caching::{Caching, CacheInstance};
+use crate::caching::cache_control::{CacheControl, expires_ttl};
+use crate::coalescing::{RequestCoalescing, Flight, FetchOutcome};
 use config::BucketConfiguration;
-use tokio::sync::Mutex;
 use std::net::SocketAddr;
 use std::collections::HashMap;
-use prometheus::{TextEncoder, Encoder, Counter, register_counter};
+use prometheus::{TextEncoder, Encoder, Counter, CounterVec, register_counter, register_counter_vec};
+use range::{RangeSpec, parse_range_header, resolve_ranges, requested_length, multipart_boundary, multipart_body};
+use conditional::{is_not_modified, not_modified_headers, if_range_matches};
+use futures::stream::{StreamExt, TryStreamExt};
 
 mod config;
-mod gcs;
+mod origin;
 mod caching;
 mod rate_limiting;
+mod range;
+mod conditional;
+mod coalescing;
+mod digest;
+#[cfg(test)]
+mod test_support;
+
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const WRITE_METHODS: &str = "PUT, POST";
+const DELETE_METHODS: &str = "DELETE";
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
 lazy_static! {
     static ref REQUEST_OK_COUNTER: Counter = register_counter!(
@@ -41,6 +55,18 @@ lazy_static! {
         "cache_misses",
         "objects not found in cache"
     ).unwrap();
+    static ref CACHE_STALE_HITS_COUNTER: Counter = register_counter!(
+        "cache_stale_hits",
+        "expired objects served from cache while being refreshed"
+    ).unwrap();
+    static ref CACHE_STALE_IF_ERROR_COUNTER: Counter = register_counter!(
+        "cache_stale_if_error",
+        "expired objects served from cache because the origin failed"
+    ).unwrap();
+    static ref CACHE_REFRESH_ERRORS_COUNTER: Counter = register_counter!(
+        "cache_refresh_errors",
+        "errors when refreshing stale objects in the background"
+    ).unwrap();
     static ref CLOUD_STORAGE_ERRORS_COUNTER: Counter = register_counter!(
         "cloud_storage_errors",
         "cloud storage errors"
@@ -51,7 +77,28 @@ lazy_static! {
     ).unwrap();
     static ref WRONG_METHOD_REQUESTS_COUNTER: Counter = register_counter!(
         "wrong_method_requests_counter",
-        "Non-get requests"
+        "requests with unsupported methods"
+    ).unwrap();
+    static ref ORIGIN_ERRORS_COUNTER: CounterVec = register_counter_vec!(
+        "origin_errors",
+        "origin errors by class",
+        &["class"]
+    ).unwrap();
+    static ref UNAUTHORIZED_REQUESTS_COUNTER: Counter = register_counter!(
+        "unauthorized_requests",
+        "write requests with a missing or wrong token"
+    ).unwrap();
+    static ref OBJECTS_UPLOADED_COUNTER: Counter = register_counter!(
+        "objects_uploaded",
+        "objects successfully uploaded to origin"
+    ).unwrap();
+    static ref OBJECTS_DELETED_COUNTER: Counter = register_counter!(
+        "objects_deleted",
+        "objects successfully deleted from origin"
+    ).unwrap();
+    static ref CACHE_DELETE_ERRORS_COUNTER: Counter = register_counter!(
+        "cache_delete_errors",
+        "errors when deleting objects from cache"
     ).unwrap();
     static ref BAD_REQUESTS_COUNTER: Counter = register_counter!(
         "bad_requests_counter",
@@ -73,20 +120,23 @@ async fn main() -> std::io::Result<()> {
     let addr = config.ip_addr().unwrap_or([0, 0, 0, 0].into());
     let addr = SocketAddr::new(addr, config.port.unwrap_or(8080));
     let cache: Arc<Caching> = Arc::new(Caching::new(config.caching.as_ref().unwrap_or(&HashMap::new())).await);
-    let client = Arc::new(Mutex::new(GoogleCloudStorageClient::new(&service_account_key(&config)).await?));
+    let origins = Arc::new(Origins::new(&config).await?);
+    let coalescing = Arc::new(RequestCoalescing::default());
 
     let make_svc = make_service_fn(move |_| {
         let config = config.clone();
-        let client = client.clone();
+        let origins = origins.clone();
         let cache = cache.clone();
+        let coalescing = coalescing.clone();
 
         async move {
             Ok::<_, Error>(service_fn(move |_req| {
                 let config = config.clone();
-                let client = client.clone();
+                let origins = origins.clone();
                 let cache = cache.clone();
+                let coalescing = coalescing.clone();
 
-                async move { proxy_service(_req, &config, client.clone(), cache.clone()).await }
+                async move { proxy_service(_req, &config, origins.clone(), cache.clone(), coalescing.clone()).await }
             }))
         }
     });
@@ -103,14 +153,18 @@ async fn main() -> std::io::Result<()> {
 async fn proxy_service(
     req: Request<Body>,
     config: &Config,
-    gcs: Arc<Mutex<GoogleCloudStorageClient>>,
+    origins: Arc<Origins>,
     cache: Arc<Caching>,
+    coalescing: Arc<RequestCoalescing>,
 ) -> Result<Response<Body>, String> {
-    if req.method() != Method::GET {
-        WRONG_METHOD_REQUESTS_COUNTER.inc();
-        BAD_REQUESTS_COUNTER.inc();
-        trace!("wrong method: {}", req.method());
-        return Ok(Response::new("wrong method".into()));
+    match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::POST | Method::DELETE => {},
+        _ => {
+            WRONG_METHOD_REQUESTS_COUNTER.inc();
+            BAD_REQUESTS_COUNTER.inc();
+            trace!("wrong method: {}", req.method());
+            return Ok(response_for_wrong_method(None));
+        }
     }
 
     if config.metrics.unwrap_or(false) || req.uri().path() == config.metrics_endpoint.as_ref().unwrap_or(&"/metrics".to_string()) {
@@ -129,142 +183,533 @@ async fn proxy_service(
         None => return Ok(Response::new("host header not set".into()))
     };
 
-    let bucket = match config.bucket_configuration_by_host(&host) {
+    let (bucket_entry_name, bucket) = match config.bucket_configuration_by_host(&host) {
         Some(v) => v,
         None => {
             BAD_REQUESTS_COUNTER.inc();
             return Ok(Response::new("unknown host".into()))
         }
     };
-    let bucket_name = match bucket.bucket.as_ref() {
-        Some(v) => v.as_str(),
+    let origin = match origins.get_origin(bucket_entry_name) {
+        Some(v) => v,
         None => {
             BAD_REQUESTS_COUNTER.inc();
             return Ok(Response::new("no origin configured".into()))
         }
     };
-    let mut object_name = req.uri().path().to_string();
+    let cache_namespace = bucket.cache_namespace(bucket_entry_name);
+    let mut object_name = match bucket.object_name(req.uri().path()) {
+        Some(v) => v,
+        None => {
+            BAD_REQUESTS_COUNTER.inc();
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("invalid object name".into())
+                .unwrap());
+        }
+    };
 
-    trace!("GET {} {}", bucket_name, object_name);
+    trace!("{} {} {}", req.method(), bucket_entry_name, object_name);
 
-    if object_name.starts_with("/") {
-        object_name = object_name[1..].into();
+    match *req.method() {
+        Method::OPTIONS => return Ok(response_for_options(bucket)),
+        Method::PUT | Method::POST => return Ok(
+            response_for_upload(req, config, bucket, &object_name, origin.as_ref(), &cache).await
+        ),
+        Method::DELETE => return Ok(
+            response_for_delete(&req, config, bucket, &object_name, origin.as_ref(), &cache).await
+        ),
+        _ => {},
     }
 
-    // TODO: handle dirs
+    // directories are served from their index object, which is generated if directory listing is enabled
     if object_name.is_empty() || object_name.ends_with("/") {
-        object_name = format!(
-            "{}{}",
-            object_name,
-            bucket.index.as_ref().unwrap_or(&"index.html".to_string()).clone()
-        );
+        object_name = format!("{}{}", object_name, bucket.index());
     }
 
-    return Ok(if let Some(cache_name) = &bucket.cache_name {
+    let generation = match query_parameter(&req, "generation").map(|v| v.parse::<u64>()) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(_)) => {
+            BAD_REQUESTS_COUNTER.inc();
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("invalid generation".into())
+                .unwrap());
+        },
+        None => None,
+    };
+
+    let ranges = req.headers().get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_range_header);
+    let ranges = ranges.as_deref();
+
+    // only single ranges are forwarded to the origin, multiple ranges are served from the full object
+    let options = GetObjectOptions {
+        range: match ranges {
+            Some([range]) => Some(range.to_header_value()),
+            _ => None,
+        },
+        generation,
+        if_none_match: req.headers().get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        if_modified_since: req.headers().get(IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        if_range: req.headers().get(IF_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+    };
+
+    let method = req.method().clone();
+
+    let mut res = if let Some(cache_name) = &bucket.cache_name {
         let cache = cache.get_cache(&cache_name);
-        if let Some(cache) = cache {
+        if let (Some(cache), Some(caching_config)) = (cache, config.caching_configuration_by_name(cache_name)) {
             debug!("using cache");
 
-            let get_from_cache_message = GetCacheEntry {
-                bucket: bucket_name.to_string(),
-                key: object_name.to_string()
+            let miss = CacheMiss {
+                origin: origin.clone(),
+                cache,
+                caching_config: caching_config.clone(),
+                method: method.clone(),
+                cache_namespace: cache_namespace.clone(),
+                object_name: object_name.clone(),
+                not_found_object_name: bucket.not_found_object_name(),
+                options: options.clone(),
             };
+            let coalescing_key = format!("{}/{}", cache_namespace, entry_key(&object_name, generation));
 
-            let res = match cache.send_get_message(get_from_cache_message).await {
-                Ok(v) => {
+            let res = match get_cache_entry(&miss).await {
+                Some(entry) if entry.is_fresh() && entry.is_not_found() => {
                     CACHE_HITS_COUNTER.inc();
-                    v
+                    not_found_page(&miss).await
                 },
-                Err(err) => {
+                Some(entry) if entry.is_fresh() => {
+                    CACHE_HITS_COUNTER.inc();
+                    entry.to_get_object_result()
+                },
+                Some(entry) if entry.is_within_stale_while_revalidate() => {
+                    CACHE_STALE_HITS_COUNTER.inc();
+                    refresh_in_background(coalescing.clone(), coalescing_key, miss);
+                    entry.to_get_object_result()
+                },
+                cached => {
                     CACHE_MISS_COUNTER.inc();
-                    warn!("failed to get object from cache: {}", err);
 
-                    let obj = match gcs.lock().await.get_object(bucket_name, &object_name).await {
-                        Ok(v) => v,
-                        Err(err) => {
-                            CLOUD_STORAGE_ERRORS_COUNTER.inc();
-                            return Ok(response_for_gcs_client_error(err, &bucket, &bucket_name, &object_name, gcs.clone()).await)
-                        }
-                    };
+                    // only plain GET requests are coalesced, their responses are the ones which get cached
+                    let is_coalescable = method == Method::GET
+                        && options.range.is_none()
+                        && options.if_none_match.is_none()
+                        && options.if_modified_since.is_none();
 
-                    let entry = CacheEntry::from_body_and_headers(obj.body, obj.headers);
-
-                    let put_cache_message = PutCacheEntry {
-                        bucket: bucket_name.to_string(),
-                        key: object_name.to_string(),
-                        entry: entry.clone(),
+                    let result = if is_coalescable {
+                        fetch_coalesced(&coalescing, coalescing_key, &miss).await
+                    } else {
+                        fetch_and_cache(&miss).await.map_err(Arc::new)
                     };
 
-                    if let Err(err) = cache.send_put_message(put_cache_message).await {
-                        CACHE_PUT_ERRORS_COUNTER.inc();
-                        error!("failed to save gcs response to cache: {}", err);
+                    match (result, cached) {
+                        (result, Some(entry)) if is_origin_failure(&result) && entry.is_within_stale_if_error() => {
+                            CACHE_STALE_IF_ERROR_COUNTER.inc();
+                            match result {
+                                Ok(v) => warn!("serving stale {}/{} after origin status {}", cache_namespace, object_name, v.status),
+                                Err(err) => warn!("serving stale {}/{} after origin error: {}", cache_namespace, object_name, err),
+                            }
+                            entry.to_get_object_result()
+                        },
+                        (Ok(v), _) => v,
+                        (Err(err), _) => {
+                            CLOUD_STORAGE_ERRORS_COUNTER.inc();
+                            return Ok(response_for_origin_error(&err, bucket, origin.as_ref()).await)
+                        }
                     }
-
-                    entry
                 }
             };
 
-            REQUEST_OK_COUNTER.inc();
-            response_for_object(&bucket, res.to_get_object_result())
+            if res.status == StatusCode::NOT_FOUND {
+                NOT_FOUND_ERRORS_COUNTER.inc();
+            } else if is_transient_status(res.status) {
+                CLOUD_STORAGE_ERRORS_COUNTER.inc();
+            } else {
+                REQUEST_OK_COUNTER.inc();
+            }
+            response_for_object_request(bucket, res, &options, ranges)
         } else {
             debug!("cache instance not found");
-            get_object_mapped_to_response(gcs.clone(), &bucket, bucket_name, &object_name).await
+            get_object_mapped_to_response(origin.as_ref(), &method, bucket, &object_name, &options, ranges).await
         }
     } else {
         debug!("skipping caching");
-        get_object_mapped_to_response(gcs.clone(), &bucket, bucket_name, &object_name).await
-    })
+        get_object_mapped_to_response(origin.as_ref(), &method, bucket, &object_name, &options, ranges).await
+    };
+
+    // a specific generation never changes, unless the object restricts caching it may be cached forever
+    let is_cacheable = res.headers().get(CACHE_CONTROL)
+        .map(|v| CacheControl::parse(v.to_str().unwrap_or("")).is_cacheable())
+        .unwrap_or(true);
+    if generation.is_some() && res.status().is_success() && is_cacheable && !bucket_sets_header(bucket, "cache-control") {
+        res.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL));
+    }
+
+    if method == Method::HEAD {
+        *res.body_mut() = Body::empty();
+    }
+
+    Ok(res)
+}
+
+fn query_parameter<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?
+        .split('&')
+        .filter_map(|v| {
+            let mut parts = v.splitn(2, '=');
+            Some((parts.next()?, parts.next().unwrap_or("")))
+        })
+        .find(|v| v.0 == name)
+        .map(|v| v.1)
+}
+
+fn bucket_sets_header(bucket: &BucketConfiguration, name: &str) -> bool {
+    bucket.headers.as_ref()
+        .map(|v| v.keys().any(|v| v.eq_ignore_ascii_case(name)))
+        .unwrap_or(false)
+}
+
+#[derive(Clone)]
+struct CacheMiss {
+    origin: Arc<dyn Origin>,
+    cache: CacheInstance,
+    caching_config: config::Caching,
+    method: Method,
+    cache_namespace: String,
+    object_name: String,
+    not_found_object_name: String,
+    options: GetObjectOptions,
+}
+
+/// Fetches an object once for all concurrent requests with the same key.
+async fn fetch_coalesced(
+    coalescing: &RequestCoalescing,
+    key: String,
+    miss: &CacheMiss,
+) -> Result<GetObjectResult, Arc<OriginError>> {
+    coalescing.fetch(key, || async { fetch_and_cache(miss).await.map_err(Arc::new) }).await
+}
+
+/// Refreshes a stale entry without delaying the response, unless a request for it is already in flight.
+fn refresh_in_background(coalescing: Arc<RequestCoalescing>, key: String, miss: CacheMiss) {
+    let guard = match coalescing.join(key) {
+        Flight::Leader(guard) => guard,
+        Flight::Follower(_) => return,
+    };
+
+    let miss = CacheMiss {
+        method: Method::GET,
+        options: GetObjectOptions {
+            generation: miss.options.generation,
+            ..GetObjectOptions::default()
+        },
+        ..miss
+    };
+
+    tokio::spawn(async move {
+        let result = fetch_and_cache(&miss).await.map_err(Arc::new);
+        match &result {
+            Ok(v) if is_transient_status(v.status) => {
+                CACHE_REFRESH_ERRORS_COUNTER.inc();
+                warn!("failed to refresh {}/{}: origin status {}", miss.cache_namespace, miss.object_name, v.status);
+            },
+            Err(err) => {
+                CACHE_REFRESH_ERRORS_COUNTER.inc();
+                warn!("failed to refresh {}/{}: {}", miss.cache_namespace, miss.object_name, err);
+            },
+            Ok(_) => {},
+        }
+        guard.complete(FetchOutcome::from_result(&result));
+    });
+}
+
+/// Origin failures during which stale entries are served, including server errors relayed by the origin.
+fn is_origin_failure(result: &Result<GetObjectResult, Arc<OriginError>>) -> bool {
+    match result {
+        Ok(v) => is_transient_status(v.status),
+        Err(err) => err.is_transient() || matches!(**err, OriginError::CircuitOpen),
+    }
+}
+
+async fn fetch_and_cache(miss: &CacheMiss) -> Result<GetObjectResult, OriginError> {
+    let obj = match fetch_object(miss.origin.as_ref(), &miss.method, &miss.object_name, &miss.options).await {
+        Err(OriginError::ObjectNotFound) if miss.caching_config.not_found_ttl() > 0 => {
+            ORIGIN_ERRORS_COUNTER.with_label_values(&[OriginError::ObjectNotFound.class()]).inc();
+            return cache_not_found(miss).await;
+        },
+        result => result?,
+    };
+
+    cache_object(miss, obj).await
+}
+
+/// Caches a fetched object if its response and metadata allow it.
+async fn cache_object(miss: &CacheMiss, obj: GetObjectResult) -> Result<GetObjectResult, OriginError> {
+    if miss.method == Method::HEAD {
+        debug!("not caching response to head request");
+        return Ok(obj);
+    } else if obj.status != StatusCode::OK {
+        debug!("not caching partial, not modified or error response");
+        return Ok(obj);
+    } else if obj.content_length().map(|v| v > miss.caching_config.max_object_size()).unwrap_or(true) {
+        debug!("object is too large to be cached, streaming it");
+        return Ok(obj);
+    }
+
+    let cache_control = CacheControl::from_headers(&obj.headers);
+    if !cache_control.is_cacheable() {
+        debug!("object cache-control does not allow caching it");
+        return Ok(obj);
+    } else if obj.headers.contains_key("set-cookie") {
+        debug!("not caching response setting cookies, they are meant for a single client");
+        return Ok(obj);
+    }
+
+    let generation = miss.options.generation;
+
+    // a specific generation of an object never changes, other objects are cached as long as their metadata says
+    let ttl = match generation {
+        Some(_) => miss.caching_config.immutable_ttl(),
+        None => miss.caching_config.clamp_ttl(cache_control.max_age()
+            .or_else(|| expires_ttl(&obj.headers))
+            .unwrap_or_else(|| miss.caching_config.ttl())),
+    };
+
+    // directives in the object metadata take precedence over the cache configuration
+    let stale_while_revalidate = cache_control.seconds("stale-while-revalidate")
+        .or(miss.caching_config.stale_while_revalidate)
+        .unwrap_or(0);
+    let stale_if_error = cache_control.seconds("stale-if-error")
+        .or(miss.caching_config.stale_if_error)
+        .unwrap_or(0);
+
+    if ttl == 0 && stale_while_revalidate == 0 && stale_if_error == 0 {
+        debug!("object is already expired, not caching it");
+        return Ok(obj);
+    }
+
+    let (body, headers) = obj.into_bytes().await?;
+
+    let entry = CacheEntry::from_body_and_headers(body, headers)
+        .with_lifetime(ttl, stale_while_revalidate, stale_if_error);
+
+    put_cache_entry(miss, &entry).await;
+    Ok(entry.to_get_object_result())
+}
+
+/// Caches a marker for a missing object, so repeated requests for it do not reach the origin, and serves
+/// the not found page.
+async fn cache_not_found(miss: &CacheMiss) -> Result<GetObjectResult, OriginError> {
+    put_not_found_marker(miss).await;
+    Ok(not_found_page(miss).await)
+}
+
+async fn put_not_found_marker(miss: &CacheMiss) {
+    let marker = CacheEntry::not_found()
+        .with_lifetime(miss.caching_config.not_found_ttl(), 0, 0);
+    put_cache_entry(miss, &marker).await;
+}
+
+/// Not found page served for a missing object. It is cached once as the object it is, under its own key,
+/// instead of along every missing object.
+async fn not_found_page(miss: &CacheMiss) -> GetObjectResult {
+    let page_miss = CacheMiss {
+        method: Method::GET,
+        object_name: miss.not_found_object_name.clone(),
+        options: GetObjectOptions::default(),
+        ..miss.clone()
+    };
+
+    let page = match get_cache_entry(&page_miss).await {
+        Some(entry) if entry.is_fresh() => Ok(entry.to_get_object_result()),
+        // a missing not found page has no not found page of its own, the plain one is served
+        _ => match fetch_object(page_miss.origin.as_ref(), &page_miss.method, &page_miss.object_name, &page_miss.options).await {
+            Ok(page) => cache_object(&page_miss, page).await,
+            Err(OriginError::ObjectNotFound) => {
+                put_not_found_marker(&page_miss).await;
+                Err(OriginError::ObjectNotFound)
+            },
+            Err(err) => Err(err),
+        },
+    };
+
+    match page {
+        Ok(mut page) if page.status == StatusCode::OK => {
+            page.status = StatusCode::NOT_FOUND;
+            page
+        },
+        Ok(page) => {
+            debug!("not found page has status {}, serving a plain one", page.status);
+            plain_not_found()
+        },
+        Err(err) => {
+            debug!("failed to get not found page, serving a plain one: {}", err);
+            plain_not_found()
+        },
+    }
+}
+
+async fn get_cache_entry(miss: &CacheMiss) -> Option<CacheEntry> {
+    let get_from_cache_message = GetCacheEntry {
+        bucket: miss.cache_namespace.clone(),
+        key: miss.object_name.clone(),
+        generation: miss.options.generation,
+    };
+
+    match miss.cache.send_get_message(get_from_cache_message).await {
+        Ok(v) => Some(v),
+        Err(err) => {
+            warn!("failed to get object from cache: {}", err);
+            None
+        }
+    }
+}
+
+async fn put_cache_entry(miss: &CacheMiss, entry: &CacheEntry) {
+    let put_cache_message = PutCacheEntry {
+        bucket: miss.cache_namespace.clone(),
+        key: miss.object_name.clone(),
+        generation: miss.options.generation,
+        ttl: Some(entry.storage_ttl()),
+        entry: entry.clone(),
+    };
+
+    if let Err(err) = miss.cache.send_put_message(put_cache_message).await {
+        CACHE_PUT_ERRORS_COUNTER.inc();
+        error!("failed to save gcs response to cache: {}", err);
+    }
+}
+
+async fn fetch_object(
+    origin: &dyn Origin,
+    method: &Method,
+    object_name: &str,
+    options: &GetObjectOptions,
+) -> Result<GetObjectResult, OriginError> {
+    match request_object(origin, method, object_name, options).await {
+        // the 416 response has to tell the client the object length, ask for it if the origin did not
+        Err(OriginError::RangeNotSatisfiable { len: None }) => {
+            let options = GetObjectOptions {
+                generation: options.generation,
+                ..GetObjectOptions::default()
+            };
+            let len = origin.head_object(object_name, &options).await.ok()
+                .and_then(|v| v.content_length());
+            Err(OriginError::RangeNotSatisfiable { len })
+        },
+        // the object changed since the client got the rest of it, it gets the whole new object instead
+        Ok(v) if v.status == StatusCode::PARTIAL_CONTENT && !options.if_range.as_deref()
+            .map(|if_range| if_range_matches(if_range, &v.headers))
+            .unwrap_or(true) => {
+            let options = GetObjectOptions {
+                range: None,
+                ..options.clone()
+            };
+            request_object(origin, method, object_name, &options).await
+        },
+        result => result,
+    }
+}
+
+async fn request_object(
+    origin: &dyn Origin,
+    method: &Method,
+    object_name: &str,
+    options: &GetObjectOptions,
+) -> Result<GetObjectResult, OriginError> {
+    if method == Method::HEAD {
+        origin.head_object(object_name, options).await
+    } else {
+        origin.get_object(object_name, options).await
+    }
 }
 
-async fn get_object_mapped_to_response(gcs: Arc<Mutex<GoogleCloudStorageClient>>, bucket: &BucketConfiguration, bucket_name: &str, object_name: &str) -> Response<Body> {
-    match gcs.lock().await.get_object(bucket_name, &object_name).await {
+async fn get_object_mapped_to_response(
+    origin: &dyn Origin,
+    method: &Method,
+    bucket: &BucketConfiguration,
+    object_name: &str,
+    options: &GetObjectOptions,
+    ranges: Option<&[RangeSpec]>,
+) -> Response<Body> {
+    match fetch_object(origin, method, object_name, options).await {
         Ok(v) => {
-            REQUEST_OK_COUNTER.inc();
-            response_for_object(&bucket, v)
+            if is_transient_status(v.status) {
+                CLOUD_STORAGE_ERRORS_COUNTER.inc();
+            } else {
+                REQUEST_OK_COUNTER.inc();
+            }
+            response_for_object_request(bucket, v, options, ranges)
         },
-        Err(err) => response_for_gcs_client_error(err, &bucket, &bucket_name, &object_name, gcs.clone()).await
+        Err(err) => response_for_origin_error(&err, bucket, origin).await
     }
 }
 
-async fn response_for_gcs_client_error(
-    err: GCSClientError, 
-    bucket: &BucketConfiguration, 
-    bucket_name: &str, 
-    _object_name: &str,
-    gcs: Arc<Mutex<GoogleCloudStorageClient>>,
+async fn response_for_origin_error(
+    err: &OriginError,
+    bucket: &BucketConfiguration,
+    origin: &dyn Origin,
 ) -> Response<Body> {
+    ORIGIN_ERRORS_COUNTER.with_label_values(&[err.class()]).inc();
+
     let is_not_found = match err {
-        GCSClientError::ObjectNotFound => true,
+        OriginError::ObjectNotFound => true,
         _ => false
     };
 
     if is_not_found {
         NOT_FOUND_ERRORS_COUNTER.inc();
 
-        let not_found_object_name = bucket.not_found.as_ref()
-            .unwrap_or(&"404.html".to_string())
-            .clone();
-
-        return match gcs.lock().await.get_object(bucket_name, &not_found_object_name).await {
-            Ok(v) => response_for_object(&bucket,v),
-            Err(_) => match Response::builder()
-                    .status(StatusCode::from_u16(404).unwrap())
-                    .body("not found.".into()) {
-                Ok(v) => v,
-                Err(err) => {
-                    INTERNAL_SERVER_ERRORS_COUNTER.inc();
-                    error!("failed to create response: {}", err);
-                    return Response::new("internal server error".into());
-                }
+        let page = match not_found_object(origin, &bucket.not_found_object_name()).await {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("failed to get not found page: {}", err);
+                plain_not_found()
             }
         };
+        return response_for_object(bucket, page);
+    }
+
+    if let OriginError::RangeNotSatisfiable { len } = err {
+        let mut res = Response::builder().status(StatusCode::RANGE_NOT_SATISFIABLE);
+        if let Some(len) = len {
+            res = res.header("content-range", format!("bytes */{}", len));
+        }
+        return res.body(Body::empty()).unwrap();
     }
 
-    error!("failed to get gcs object: {}", err);
+    let (status, message) = match err {
+        OriginError::InvalidObjectName { .. } => {
+            BAD_REQUESTS_COUNTER.inc();
+            (StatusCode::BAD_REQUEST, "invalid object name")
+        },
+        OriginError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+        OriginError::PreconditionFailed => (StatusCode::PRECONDITION_FAILED, "precondition failed"),
+        OriginError::RateLimited | OriginError::CircuitOpen => (StatusCode::SERVICE_UNAVAILABLE, "origin unavailable"),
+        OriginError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "origin timed out"),
+        OriginError::OperationNotSupported { .. } => (StatusCode::NOT_IMPLEMENTED, "not supported by origin"),
+        OriginError::Unauthorized
+            | OriginError::ServerError { .. }
+            | OriginError::RequestFailed { .. }
+            | OriginError::UnexpectedStatus { .. } => (StatusCode::BAD_GATEWAY, "failed to get object"),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "failed to get object"),
+    };
+
+    if status.is_server_error() {
+        error!("failed to get object from origin: {}", err);
+    }
 
     match Response::builder()
-        .status(StatusCode::from_u16(500).unwrap())
-        .body("failed to get gcs object".into()) {
+        .status(status)
+        .body(message.into()) {
         Ok(v) => v,
         Err(err) => {
             INTERNAL_SERVER_ERRORS_COUNTER.inc();
@@ -274,9 +719,120 @@ async fn response_for_gcs_client_error(
     }
 }
 
+/// The not found page of the bucket, or a plain message if it has none. Fails if the page could not
+/// be fetched because of an origin failure.
+async fn not_found_object(origin: &dyn Origin, not_found_object_name: &str) -> Result<GetObjectResult, OriginError> {
+    match origin.get_object(not_found_object_name, &GetObjectOptions::default()).await {
+        Ok(v) if is_transient_status(v.status) => Err(OriginError::from_status(v.status)),
+        Ok(v) => Ok(GetObjectResult {
+            status: StatusCode::NOT_FOUND,
+            ..v
+        }),
+        Err(err) if err.is_transient() || matches!(err, OriginError::CircuitOpen) => Err(err),
+        Err(_) => Ok(plain_not_found()),
+    }
+}
+
+fn plain_not_found() -> GetObjectResult {
+    let body = b"not found.".to_vec();
+    let mut headers = HashMap::new();
+    headers.insert("content-length".to_string(), body.len().to_string());
+
+    GetObjectResult {
+        status: StatusCode::NOT_FOUND,
+        body: ObjectBody::Bytes(body),
+        headers,
+    }
+}
+
+fn response_for_object_request(
+    config: &BucketConfiguration,
+    object: GetObjectResult,
+    options: &GetObjectOptions,
+    ranges: Option<&[RangeSpec]>,
+) -> Response<Body> {
+    let not_modified = object.status == StatusCode::NOT_MODIFIED || (object.status == StatusCode::OK && is_not_modified(
+        options.if_none_match.as_deref(),
+        options.if_modified_since.as_deref(),
+        &object.headers,
+    ));
+
+    if not_modified {
+        return response_for_object(config, GetObjectResult {
+            status: StatusCode::NOT_MODIFIED,
+            body: ObjectBody::Bytes(Vec::new()),
+            headers: not_modified_headers(object.headers),
+        });
+    }
+
+    response_for_object_with_ranges(config, object, ranges, options.if_range.as_deref())
+}
+
+fn response_for_object_with_ranges(
+    config: &BucketConfiguration,
+    object: GetObjectResult,
+    ranges: Option<&[RangeSpec]>,
+    if_range: Option<&str>,
+) -> Response<Body> {
+    let ranges = match ranges {
+        Some(v) if object.status == StatusCode::OK => v,
+        _ => return response_for_object(config, object),
+    };
+
+    if let Some(if_range) = if_range {
+        if !if_range_matches(if_range, &object.headers) {
+            return response_for_object(config, object);
+        }
+    }
+
+    // ranges are only sliced out of buffered (cached) objects, streamed objects are served in full
+    let body = match object.body {
+        ObjectBody::Bytes(v) => v,
+        body => return response_for_object(config, GetObjectResult { body, ..object }),
+    };
+
+    let total_len = body.len() as u64;
+
+    // overlapping ranges could otherwise make the response many times larger than the object
+    if requested_length(ranges, total_len) > total_len {
+        return response_for_object(config, GetObjectResult { body: ObjectBody::Bytes(body), ..object });
+    }
+
+    let ranges = resolve_ranges(ranges, total_len);
+    let mut headers = object.headers;
+    headers.remove("content-length");
+
+    let body = match ranges.as_slice() {
+        [] => return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("content-range", format!("bytes */{}", total_len))
+            .body(Body::empty())
+            .unwrap(),
+        [range] => {
+            headers.insert("content-range".to_string(), range.content_range(total_len));
+            body[range.start as usize..=range.end as usize].to_vec()
+        },
+        ranges => {
+            let boundary = multipart_boundary();
+            let body = multipart_body(&body, ranges, headers.get("content-type").map(|v| v.as_str()), &boundary);
+            headers.insert("content-type".to_string(), format!("multipart/byteranges; boundary={}", boundary));
+            body
+        }
+    };
+
+    headers.insert("content-length".to_string(), body.len().to_string());
+
+    response_for_object(config, GetObjectResult {
+        status: StatusCode::PARTIAL_CONTENT,
+        body: ObjectBody::Bytes(body),
+        headers,
+    })
+}
+
 fn response_for_object(config: &BucketConfiguration, object: GetObjectResult) -> Response<Body> {
     let mut res = Response::builder()
-        .status(StatusCode::from_u16(200).unwrap())
+        .status(object.status)
+        .header("accept-ranges", "bytes")
         .body(object.body.into()).unwrap();
 
     let headers = res.headers_mut();
@@ -294,6 +850,198 @@ fn response_for_object(config: &BucketConfiguration, object: GetObjectResult) ->
     return res;
 }
 
+fn allowed_methods(bucket: Option<&BucketConfiguration>) -> String {
+    let mut methods = vec![ALLOWED_METHODS];
+    if bucket.map(|v| v.write_token.is_some()).unwrap_or(false) {
+        methods.push(WRITE_METHODS);
+    }
+    if bucket.map(|v| v.delete_token.is_some()).unwrap_or(false) {
+        methods.push(DELETE_METHODS);
+    }
+    methods.join(", ")
+}
+
+fn response_for_options(bucket: &BucketConfiguration) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, allowed_methods(Some(bucket)))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn response_for_wrong_method(bucket: Option<&BucketConfiguration>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, allowed_methods(bucket))
+        .body("method not allowed".into())
+        .unwrap()
+}
+
+fn response_for_unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body("unauthorized".into())
+        .unwrap()
+}
+
+fn is_authorized(req: &Request<Body>, expected_token: &str) -> bool {
+    let token = match req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(v) if v.starts_with("Bearer ") => &v["Bearer ".len()..],
+        _ => return false,
+    };
+
+    token.len() == expected_token.len() && openssl::memcmp::eq(token.as_bytes(), expected_token.as_bytes())
+}
+
+/// Error response for a write request without `token` or not targeting a single object, if any. Buckets
+/// without the token do not accept the request method at all.
+fn response_for_rejected_write(
+    req: &Request<Body>,
+    bucket: &BucketConfiguration,
+    token: Option<&str>,
+    object_name: &str,
+) -> Option<Response<Body>> {
+    let token = match token {
+        Some(v) => v,
+        None => {
+            WRONG_METHOD_REQUESTS_COUNTER.inc();
+            BAD_REQUESTS_COUNTER.inc();
+            return Some(response_for_wrong_method(Some(bucket)));
+        }
+    };
+
+    if !is_authorized(req, token) {
+        UNAUTHORIZED_REQUESTS_COUNTER.inc();
+        return Some(response_for_unauthorized());
+    }
+
+    if object_name.is_empty() || object_name.ends_with('/') {
+        BAD_REQUESTS_COUNTER.inc();
+        return Some(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("invalid object name".into())
+            .unwrap());
+    }
+
+    None
+}
+
+async fn response_for_upload(
+    req: Request<Body>,
+    config: &Config,
+    bucket: &BucketConfiguration,
+    object_name: &str,
+    origin: &dyn Origin,
+    cache: &Caching,
+) -> Response<Body> {
+    if let Some(res) = response_for_rejected_write(&req, bucket, bucket.write_token.as_deref(), object_name) {
+        return res;
+    }
+
+    let options = PutObjectOptions {
+        content_length: req.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        content_type: req.headers().get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+    };
+    let body = req.into_body().map_err(|err| err.into()).boxed();
+
+    if let Err(err) = origin.put_object(object_name, body, &options).await {
+        CLOUD_STORAGE_ERRORS_COUNTER.inc();
+        return response_for_origin_error(&err, bucket, origin).await;
+    }
+
+    OBJECTS_UPLOADED_COUNTER.inc();
+    invalidate_cached_object(config, bucket, object_name, cache).await;
+
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn response_for_delete(
+    req: &Request<Body>,
+    config: &Config,
+    bucket: &BucketConfiguration,
+    object_name: &str,
+    origin: &dyn Origin,
+    cache: &Caching,
+) -> Response<Body> {
+    if let Some(res) = response_for_rejected_write(req, bucket, bucket.delete_token.as_deref(), object_name) {
+        return res;
+    }
+
+    match origin.delete_object(object_name).await {
+        Ok(()) => {},
+        Err(OriginError::ObjectNotFound) => {
+            NOT_FOUND_ERRORS_COUNTER.inc();
+            // the object may still be cached if it was deleted bypassing the proxy
+            invalidate_cached_object(config, bucket, object_name, cache).await;
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("not found.".into())
+                .unwrap();
+        },
+        Err(err) => {
+            CLOUD_STORAGE_ERRORS_COUNTER.inc();
+            return response_for_origin_error(&err, bucket, origin).await;
+        }
+    }
+
+    OBJECTS_DELETED_COUNTER.inc();
+    invalidate_cached_object(config, bucket, object_name, cache).await;
+
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Removes a changed object from the caches of all hosts serving it from the same origin, together with
+/// the generated listing of its directory.
+async fn invalidate_cached_object(config: &Config, bucket: &BucketConfiguration, object_name: &str, cache: &Caching) {
+    let (entry_name, _) = match config.bucket_configuration_by_host(&bucket.host) {
+        Some(v) => v,
+        None => return,
+    };
+    let origin_namespace = bucket.origin_namespace(entry_name);
+    let directory = match object_name.rfind('/') {
+        Some(v) => &object_name[..=v],
+        None => "",
+    };
+
+    let hosts = config.buckets.iter()
+        .flatten()
+        .filter(|(name, host)| host.origin_namespace(name) == origin_namespace);
+    for (name, host) in hosts {
+        let host_cache = match host.cache_name.as_ref().and_then(|v| cache.get_cache(v)) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let mut keys = vec![object_name.to_string()];
+        if host.directory_listing.is_some() {
+            keys.push(format!("{}{}", directory, host.index()));
+        }
+
+        for key in keys {
+            let delete_cache_message = DeleteCacheEntry {
+                bucket: host.cache_namespace(name),
+                key,
+            };
+
+            if let Err(err) = host_cache.send_delete_message(delete_cache_message).await {
+                CACHE_DELETE_ERRORS_COUNTER.inc();
+                warn!("failed to delete object from cache: {}", err);
+            }
+        }
+    }
+}
+
 fn response_for_metrics_endpoint() -> Response<Body> {
     let mut buffer = vec![];
     let encoder = TextEncoder::new();
@@ -314,16 +1062,3 @@ fn response_for_metrics_endpoint() -> Response<Body> {
 
     Response::builder().status(StatusCode::from_u16(200).unwrap()).body(encoded.into()).unwrap()
 }
-
-fn service_account_key(config: &Config) -> String {
-    match &config.service_account_key {
-        Some(v) => v.to_string(),
-        None => fs::read_to_string(
-            &config.clone().service_account_key_file.unwrap_or(get_service_account_key_file_name())
-        ).expect("failed to read service account key file")
-    }
-}
-
-fn get_service_account_key_file_name() -> String {
-    var("SERVICE_ACCOUNT_KEY_FILE").unwrap_or("service_account_key.json".into())
-}
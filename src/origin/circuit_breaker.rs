@@ -0,0 +1,204 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use prometheus::{Counter, GaugeVec, register_counter, register_gauge_vec};
+use crate::origin::object::{OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ListObjectsResult, BodyStream};
+use crate::origin::origin::Origin;
+use crate::origin::retry::{OriginResponse, is_transient_failure};
+
+const STATE_CLOSED: f64 = 0.0;
+const STATE_OPEN: f64 = 1.0;
+const STATE_HALF_OPEN: f64 = 2.0;
+
+lazy_static! {
+    static ref CIRCUIT_BREAKER_STATE: GaugeVec = register_gauge_vec!(
+        "circuit_breaker_state",
+        "origin circuit breaker state: 0 - closed, 1 - open, 2 - half-open",
+        &["bucket"]
+    ).unwrap();
+    static ref CIRCUIT_BREAKER_REJECTED_COUNTER: Counter = register_counter!(
+        "circuit_breaker_rejected_requests",
+        "origin requests rejected because the circuit breaker was open"
+    ).unwrap();
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+}
+
+/// Marks the single request let through a half-open breaker, the next one may probe once it is dropped.
+struct Probe<'a> {
+    state: &'a Mutex<BreakerState>,
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().probe_in_flight = false;
+    }
+}
+
+/// Stops sending requests to an origin after `failure_threshold` consecutive transient failures.
+/// Once `reset_timeout` passes a single probe request is let through, others are still rejected: its
+/// success closes the breaker, a failure opens it for another `reset_timeout`.
+pub struct CircuitBreakerOrigin {
+    origin: Arc<dyn Origin>,
+    bucket: String,
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreakerOrigin {
+
+    pub fn new(origin: Arc<dyn Origin>, bucket: String, failure_threshold: u32, reset_timeout: Duration) -> Self {
+        CIRCUIT_BREAKER_STATE.with_label_values(&[&bucket]).set(STATE_CLOSED);
+
+        Self {
+            origin,
+            bucket,
+            failure_threshold: failure_threshold.max(1),
+            reset_timeout,
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_in_flight: false,
+            }),
+        }
+    }
+
+    fn before_request(&self) -> Result<Option<Probe<'_>>, OriginError> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.opened_at {
+            Some(opened_at) if opened_at.elapsed() < self.reset_timeout || state.probe_in_flight => {
+                CIRCUIT_BREAKER_REJECTED_COUNTER.inc();
+                Err(OriginError::CircuitOpen)
+            },
+            Some(_) => {
+                self.set_state_metric(STATE_HALF_OPEN);
+                state.probe_in_flight = true;
+                Ok(Some(Probe { state: &self.state }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn after_request<T: OriginResponse>(&self, result: &Result<T, OriginError>) {
+        let mut state = self.state.lock().unwrap();
+
+        if is_transient_failure(result) {
+            state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+            if state.consecutive_failures >= self.failure_threshold {
+                if state.opened_at.is_none() {
+                    warn!("circuit breaker for {} is open after {} failures", self.bucket, state.consecutive_failures);
+                }
+                state.opened_at = Some(Instant::now());
+                self.set_state_metric(STATE_OPEN);
+            }
+        } else {
+            if state.opened_at.is_some() {
+                info!("circuit breaker for {} is closed", self.bucket);
+            }
+            state.consecutive_failures = 0;
+            state.opened_at = None;
+            self.set_state_metric(STATE_CLOSED);
+        }
+    }
+
+    fn set_state_metric(&self, state: f64) {
+        CIRCUIT_BREAKER_STATE.with_label_values(&[&self.bucket]).set(state);
+    }
+
+    async fn guarded<T, F>(&self, request: F) -> Result<T, OriginError>
+        where F: std::future::Future<Output=Result<T, OriginError>>, T: OriginResponse {
+        let probe = self.before_request()?;
+        let result = request.await;
+        self.after_request(&result);
+        drop(probe);
+        result
+    }
+}
+
+#[async_trait]
+impl Origin for CircuitBreakerOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.guarded(self.origin.get_object(object, options)).await
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.guarded(self.origin.head_object(object, options)).await
+    }
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        self.guarded(self.origin.list_objects(prefix, delimiter, page_token)).await
+    }
+
+    async fn put_object(&self, object: &str, body: BodyStream, options: &PutObjectOptions) -> Result<(), OriginError> {
+        self.origin.put_object(object, body, options).await
+    }
+
+    async fn delete_object(&self, object: &str) -> Result<(), OriginError> {
+        self.guarded(self.origin.delete_object(object)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use hyper::StatusCode;
+    use crate::origin::object::ObjectBody;
+
+    /// Fails the first request, then answers slowly.
+    struct RecoveringOrigin {
+        failed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Origin for RecoveringOrigin {
+
+        async fn get_object(&self, _object: &str, _options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                return Err(OriginError::ServerError { status: StatusCode::SERVICE_UNAVAILABLE });
+            }
+
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            Ok(GetObjectResult {
+                status: StatusCode::OK,
+                body: ObjectBody::Bytes(Vec::new()),
+                headers: HashMap::new(),
+            })
+        }
+
+        async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+            self.get_object(object, options).await
+        }
+
+        async fn list_objects(&self, _prefix: &str, _delimiter: Option<&str>, _page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+            Err(OriginError::OperationNotSupported { operation: "list objects".to_string() })
+        }
+    }
+
+    #[tokio::test]
+    async fn lets_a_single_probe_through_when_half_open() {
+        let origin = Arc::new(RecoveringOrigin { failed: AtomicBool::new(false) });
+        let breaker = CircuitBreakerOrigin::new(origin, "test".to_string(), 1, Duration::from_millis(0));
+        let options = GetObjectOptions::default();
+
+        assert!(breaker.get_object("a", &options).await.is_err());
+
+        let (probe, concurrent) = futures::join!(breaker.get_object("a", &options), async {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            breaker.get_object("a", &options).await
+        });
+        assert!(probe.is_ok());
+        assert!(matches!(concurrent, Err(OriginError::CircuitOpen)));
+
+        // the successful probe closed the breaker
+        assert!(breaker.get_object("a", &options).await.is_ok());
+    }
+}
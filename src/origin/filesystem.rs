@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf, Component};
+use std::time::UNIX_EPOCH;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use hyper::StatusCode;
+use httpdate::fmt_http_date;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use crate::origin::object::{decode_object_name, OriginError, GetObjectResult, GetObjectOptions, ListObjectsResult, ObjectInfo, ObjectBody};
+use crate::origin::origin::Origin;
+use crate::range::parse_range_header;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Origin serving files from a local directory.
+pub struct FilesystemOrigin {
+    directory: PathBuf,
+}
+
+impl FilesystemOrigin {
+
+    pub fn new(directory: String) -> Self {
+        Self {
+            directory: PathBuf::from(directory),
+        }
+    }
+
+    fn resolve_path(&self, object: &str) -> Result<PathBuf, OriginError> {
+        let object = decode_object_name(object)?;
+        let relative = Path::new(&object);
+
+        // decoded names may still be absolute paths, which would replace the directory when joined
+        if relative.components().any(|v| !matches!(v, Component::Normal(_))) {
+            return Err(OriginError::InvalidObjectName { name: object });
+        }
+
+        Ok(self.directory.join(relative))
+    }
+
+    async fn request_object(&self, object: &str, options: &GetObjectOptions, include_body: bool) -> Result<GetObjectResult, OriginError> {
+        if options.generation.is_some() {
+            return Err(OriginError::OperationNotSupported { operation: "object generations".to_string() });
+        }
+
+        let path = self.resolve_path(object)?;
+
+        let mut file = match File::open(&path).await {
+            Ok(v) => v,
+            Err(_) => return Err(OriginError::ObjectNotFound),
+        };
+        let metadata = file.metadata().await
+            .map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })?;
+        if !metadata.is_file() {
+            return Err(OriginError::ObjectNotFound);
+        }
+
+        let len = metadata.len();
+        let mut headers = HashMap::new();
+        headers.insert(
+            "content-type".to_string(),
+            mime_guess::from_path(&path).first_or_octet_stream().to_string()
+        );
+
+        if let Ok(modified) = metadata.modified() {
+            let modified_secs = modified.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0);
+            headers.insert("last-modified".to_string(), fmt_http_date(modified));
+            headers.insert("etag".to_string(), format!("\"{:x}-{:x}\"", modified_secs, len));
+        }
+
+        let range = match options.range.as_deref().and_then(parse_range_header).as_deref() {
+            Some([range]) => Some(range.resolve(len).ok_or(OriginError::RangeNotSatisfiable { len: Some(len) })?),
+            _ => None,
+        };
+
+        let (status, start, body_len) = match range {
+            Some(range) => {
+                headers.insert("content-range".to_string(), range.content_range(len));
+                (StatusCode::PARTIAL_CONTENT, range.start, range.end - range.start + 1)
+            },
+            None => (StatusCode::OK, 0, len),
+        };
+        headers.insert("content-length".to_string(), body_len.to_string());
+
+        if !include_body {
+            return Ok(GetObjectResult {
+                status,
+                body: ObjectBody::Stream(stream::empty().boxed()),
+                headers,
+            });
+        }
+
+        file.seek(SeekFrom::Start(start)).await
+            .map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })?;
+
+        let body = stream::unfold((file, body_len), |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+
+            let mut buffer = vec![0; READ_CHUNK_SIZE.min(remaining as usize)];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(Bytes::from(buffer)), (file, remaining - read as u64)))
+                },
+                Err(err) => Some((Err(err.into()), (file, 0))),
+            }
+        });
+
+        Ok(GetObjectResult {
+            status,
+            body: ObjectBody::Stream(body.boxed()),
+            headers,
+        })
+    }
+}
+
+#[async_trait]
+impl Origin for FilesystemOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(object, options, true).await
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(object, options, false).await
+    }
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, _page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        // listing starts at the directory containing the prefix, entries are then filtered by the full prefix
+        let directory_prefix = match prefix.rfind('/') {
+            Some(v) => &prefix[..=v],
+            None => "",
+        };
+        let directory = self.resolve_path(directory_prefix)?;
+        let prefix = prefix.to_string();
+        let directory_prefix = directory_prefix.to_string();
+        let recursive = delimiter.is_none();
+
+        tokio::task::spawn_blocking(move || {
+            let mut result = ListObjectsResult {
+                objects: Vec::new(),
+                prefixes: Vec::new(),
+                next_page_token: None,
+            };
+            list_directory(&directory, &directory_prefix, &prefix, recursive, &mut result);
+            result.objects.sort_by(|a, b| a.name.cmp(&b.name));
+            result.prefixes.sort();
+            result
+        }).await.map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })
+    }
+}
+
+fn list_directory(directory: &Path, name_prefix: &str, prefix: &str, recursive: bool, result: &mut ListObjectsResult) {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|v| v.ok()) {
+        let metadata = match entry.metadata() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let name = format!("{}{}", name_prefix, entry.file_name().to_string_lossy());
+
+        if metadata.is_dir() {
+            let name = format!("{}/", name);
+            if !name.starts_with(prefix) && !prefix.starts_with(&name) {
+                continue;
+            }
+
+            if recursive {
+                list_directory(&entry.path(), &name, prefix, recursive, result);
+            } else if name.starts_with(prefix) {
+                result.prefixes.push(name);
+            }
+        } else if name.starts_with(prefix) {
+            result.objects.push(ObjectInfo {
+                name,
+                size: metadata.len(),
+                updated: metadata.modified().ok().map(|v| chrono::DateTime::<chrono::Utc>::from(v).to_rfc3339()),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+
+    /// Origin serving a fresh temporary directory with `a.txt` and `sub/b.txt`.
+    fn origin(name: &str) -> FilesystemOrigin {
+        let directory = std::env::temp_dir().join(format!("cloud-storage-proxy-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("sub")).unwrap();
+        std::fs::write(directory.join("a.txt"), "hello world").unwrap();
+        std::fs::write(directory.join("sub/b.txt"), "b").unwrap();
+        FilesystemOrigin::new(directory.to_string_lossy().to_string())
+    }
+
+    async fn body(result: GetObjectResult) -> Vec<u8> {
+        hyper::body::to_bytes(Body::from(result.body)).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn serves_files() {
+        let origin = origin("serves");
+
+        let result = origin.get_object("a.txt", &GetObjectOptions::default()).await.unwrap();
+        assert_eq!(result.status, StatusCode::OK);
+        assert_eq!(result.headers["content-type"], "text/plain");
+        assert_eq!(result.headers["content-length"], "11");
+        assert_eq!(body(result).await, b"hello world");
+
+        let result = origin.get_object("sub%2Fb.txt", &GetObjectOptions::default()).await.unwrap();
+        assert_eq!(body(result).await, b"b");
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let origin = origin("ranges");
+        let options = |range: &str| GetObjectOptions { range: Some(range.to_string()), ..GetObjectOptions::default() };
+
+        let result = origin.get_object("a.txt", &options("bytes=6-")).await.unwrap();
+        assert_eq!(result.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(result.headers["content-range"], "bytes 6-10/11");
+        assert_eq!(result.headers["content-length"], "5");
+        assert_eq!(body(result).await, b"world");
+
+        match origin.get_object("a.txt", &options("bytes=20-")).await {
+            Err(OriginError::RangeNotSatisfiable { len }) => assert_eq!(len, Some(11)),
+            _ => panic!("expected an unsatisfiable range"),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_head_requests_without_body() {
+        let origin = origin("head");
+
+        let result = origin.head_object("a.txt", &GetObjectOptions::default()).await.unwrap();
+        assert_eq!(result.status, StatusCode::OK);
+        assert_eq!(result.headers["content-length"], "11");
+        assert!(result.headers.contains_key("etag"));
+        assert!(body(result).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_names_outside_of_the_directory() {
+        let origin = origin("outside");
+
+        for name in &["../a.txt", "sub/../../a.txt", "%2e%2e/a.txt", "sub/%2E%2E/%2e%2e/a.txt", "%2Fetc%2Fpasswd", "./a.txt"] {
+            match origin.get_object(name, &GetObjectOptions::default()).await {
+                Err(OriginError::InvalidObjectName { .. }) => {},
+                _ => panic!("{} was not rejected", name),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_serve_directories_or_missing_files() {
+        let origin = origin("directories");
+
+        for name in &["sub", "sub/", "missing.txt"] {
+            match origin.get_object(name, &GetObjectOptions::default()).await {
+                Err(OriginError::ObjectNotFound) => {},
+                _ => panic!("{} was served", name),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_directories() {
+        let origin = origin("listing");
+
+        let result = origin.list_objects("", Some("/"), None).await.unwrap();
+        assert_eq!(result.objects.iter().map(|v| v.name.as_str()).collect::<Vec<_>>(), vec!["a.txt"]);
+        assert_eq!(result.prefixes, vec!["sub/"]);
+
+        let result = origin.list_objects("", None, None).await.unwrap();
+        assert_eq!(result.objects.iter().map(|v| v.name.as_str()).collect::<Vec<_>>(), vec!["a.txt", "sub/b.txt"]);
+    }
+}
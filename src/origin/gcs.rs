@@ -0,0 +1,535 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use hyper::StatusCode;
+use percent_encoding::utf8_percent_encode;
+use crate::origin::gcs_auth::TokenSource;
+use crate::origin::object::{decode_object_name, encode_object_path, UNRESERVED_ENCODE_SET, OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ListObjectsResult, ObjectInfo, BodyStream};
+use crate::origin::origin::{client_builder, send_request, Origin};
+
+const DEFAULT_STORAGE_ENDPOINT: &str = "https://storage.googleapis.com";
+
+// bodies of unknown length or larger than this are uploaded with resumable uploads
+const RESUMABLE_UPLOAD_THRESHOLD: u64 = 8 * 1024 * 1024;
+// resumable upload chunks have to be a multiple of 256 KiB
+const UPLOAD_CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+pub struct GoogleCloudStorageClient {
+    token_source: TokenSource,
+    scope: String,
+    reqwest_client: reqwest::Client,
+    request_timeout: Option<Duration>,
+    endpoint: String,
+}
+
+impl GoogleCloudStorageClient {
+
+    pub fn new(
+        token_source: TokenSource,
+        scope: String,
+        endpoint: Option<String>,
+        connection_pool_size: Option<usize>,
+        request_timeout: Option<Duration>,
+    ) -> Result<Self, OriginError> {
+        let reqwest_client = client_builder(connection_pool_size, request_timeout).build()?;
+
+        Ok(GoogleCloudStorageClient {
+            token_source,
+            scope,
+            reqwest_client,
+            request_timeout,
+            endpoint: endpoint
+                .map(|v| v.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| DEFAULT_STORAGE_ENDPOINT.to_string()),
+        })
+    }
+
+    pub async fn get_object(&self, bucket_name: &str, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(reqwest::Method::GET, bucket_name, object, options).await
+    }
+
+    /// Same as `get_object`, but only fetches object metadata. The body of the result is empty.
+    pub async fn head_object(&self, bucket_name: &str, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(reqwest::Method::HEAD, bucket_name, object, options).await
+    }
+
+    async fn request_object(
+        &self,
+        method: reqwest::Method,
+        bucket_name: &str,
+        object: &str,
+        options: &GetObjectOptions,
+    ) -> Result<GetObjectResult, OriginError> {
+        let url = format!(
+            "{}/{}/{}",
+            self.endpoint,
+            bucket_name,
+            encode_object_path(object)?
+        );
+
+        let mut req = self.authorize(self.reqwest_client.request(method, &url)).await?;
+
+        if let Some(generation) = options.generation {
+            req = req.query(&[("generation", generation)]);
+        }
+
+        if let Some(range) = &options.range {
+            req = req.header("Range", range);
+        }
+
+        if let Some(if_none_match) = &options.if_none_match {
+            req = req.header("If-None-Match", if_none_match);
+        }
+
+        if let Some(if_modified_since) = &options.if_modified_since {
+            req = req.header("If-Modified-Since", if_modified_since);
+        }
+
+        let res = send_request(req, self.request_timeout).await?;
+
+        GetObjectResult::from_storage_response(res).await
+    }
+
+    pub async fn list_objects(
+        &self,
+        bucket_name: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        page_token: Option<&str>,
+    ) -> Result<ListObjectsResult, OriginError> {
+        let url = format!("{}/storage/v1/b/{}/o", self.endpoint, bucket_name);
+
+        let mut query = vec![("prefix", prefix)];
+        if let Some(delimiter) = delimiter {
+            query.push(("delimiter", delimiter));
+        }
+        if let Some(page_token) = page_token {
+            query.push(("pageToken", page_token));
+        }
+
+        let req = self.authorize(self.reqwest_client.get(&url).query(&query)).await?;
+        let res = send_request(req, self.request_timeout).await?;
+
+        if !res.status().is_success() {
+            return Err(OriginError::from_status(res.status()));
+        }
+
+        let res: ListObjectsResponse = serde_json::from_slice(&res.bytes().await?)
+            .map_err(|err| OriginError::FailedToParseResponse { details: format!("{}", err) })?;
+
+        Ok(ListObjectsResult {
+            objects: res.items.into_iter()
+                .map(|v| ObjectInfo {
+                    name: v.name,
+                    size: v.size.and_then(|v| v.parse().ok()).unwrap_or(0),
+                    updated: v.updated,
+                })
+                .collect(),
+            prefixes: res.prefixes,
+            next_page_token: res.next_page_token,
+        })
+    }
+
+    pub async fn put_object(
+        &self,
+        bucket_name: &str,
+        object: &str,
+        body: BodyStream,
+        options: &PutObjectOptions,
+    ) -> Result<(), OriginError> {
+        let url = format!("{}/upload/storage/v1/b/{}/o", self.endpoint, bucket_name);
+        let name = decode_object_name(object)?;
+        let content_type = options.content_type.as_deref().unwrap_or("application/octet-stream");
+
+        match options.content_length {
+            Some(content_length) if content_length <= RESUMABLE_UPLOAD_THRESHOLD => {
+                let body = read_body(body, content_length as usize).await?;
+
+                let req = self.authorize(self.reqwest_client.post(&url)).await?
+                    .query(&[("uploadType", "media"), ("name", &name)])
+                    .header("Content-Type", content_type)
+                    .body(body);
+                let res = send_request(req, self.request_timeout).await?;
+
+                if !res.status().is_success() {
+                    return Err(OriginError::from_status(res.status()));
+                }
+
+                Ok(())
+            },
+            _ => self.resumable_upload(&url, &name, content_type, body, options.content_length).await,
+        }
+    }
+
+    pub async fn delete_object(&self, bucket_name: &str, object: &str) -> Result<(), OriginError> {
+        let name = decode_object_name(object)?;
+        // object names are a single path segment in the json api, so slashes are encoded as well
+        let url = format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.endpoint,
+            bucket_name,
+            utf8_percent_encode(&name, UNRESERVED_ENCODE_SET)
+        );
+
+        let req = self.authorize(self.reqwest_client.delete(&url)).await?;
+        let res = send_request(req, self.request_timeout).await?;
+
+        if !res.status().is_success() {
+            return Err(OriginError::from_status(res.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Uploads the body in chunks, so that only a single chunk is kept in memory at a time.
+    async fn resumable_upload(
+        &self,
+        url: &str,
+        name: &str,
+        content_type: &str,
+        mut body: BodyStream,
+        content_length: Option<u64>,
+    ) -> Result<(), OriginError> {
+        let mut req = self.authorize(self.reqwest_client.post(url)).await?
+            .query(&[("uploadType", "resumable"), ("name", name)])
+            .header("X-Upload-Content-Type", content_type)
+            .header("Content-Length", "0");
+        if let Some(content_length) = content_length {
+            req = req.header("X-Upload-Content-Length", content_length);
+        }
+
+        let res = send_request(req, self.request_timeout).await?;
+        if !res.status().is_success() {
+            return Err(OriginError::from_status(res.status()));
+        }
+
+        let session_url = res.headers().get("location")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| OriginError::FailedToParseResponse { details: "no resumable upload session url".to_string() })?
+            .to_string();
+
+        let mut buffer = Vec::with_capacity(UPLOAD_CHUNK_SIZE + 1);
+        let mut offset: u64 = 0;
+        let mut body_finished = false;
+
+        loop {
+            // one byte more than a chunk is needed to know whether the chunk is the last one
+            while buffer.len() <= UPLOAD_CHUNK_SIZE && !body_finished {
+                match body.next().await {
+                    Some(chunk) => buffer.extend_from_slice(
+                        &chunk.map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })?
+                    ),
+                    None => body_finished = true,
+                }
+            }
+
+            let is_last = buffer.len() <= UPLOAD_CHUNK_SIZE;
+            let chunk: Vec<u8> = if is_last {
+                buffer.split_off(0)
+            } else {
+                buffer.drain(..UPLOAD_CHUNK_SIZE).collect()
+            };
+            let chunk_len = chunk.len() as u64;
+
+            let content_range = match (chunk_len, is_last) {
+                (0, _) => format!("bytes */{}", offset),
+                (_, true) => format!("bytes {}-{}/{}", offset, offset + chunk_len - 1, offset + chunk_len),
+                (_, false) => format!("bytes {}-{}/*", offset, offset + chunk_len - 1),
+            };
+
+            let req = self.authorize(self.reqwest_client.put(&session_url)).await?
+                .header("Content-Range", content_range)
+                .body(chunk);
+            let res = send_request(req, self.request_timeout).await?;
+
+            if is_last {
+                if !res.status().is_success() {
+                    return Err(OriginError::from_status(res.status()));
+                }
+                return Ok(());
+            }
+
+            // 308 means the chunk was persisted and more data is expected
+            if res.status() != StatusCode::PERMANENT_REDIRECT {
+                return Err(OriginError::from_status(res.status()));
+            }
+
+            offset += chunk_len;
+            let persisted = res.headers().get("range")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('-').next())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(|v| v + 1);
+            if persisted != Some(offset) {
+                return Err(OriginError::FailedToParseResponse {
+                    details: format!("upload chunk was not fully persisted, expected {} bytes", offset)
+                });
+            }
+        }
+    }
+
+    async fn authorize(&self, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, OriginError> {
+        Ok(match self.token_source.token(&[&self.scope]).await? {
+            Some(access_token) => req.header("Authorization", format!("Bearer {}", access_token)),
+            None => req,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListObjectsResponse {
+    #[serde(default)]
+    items: Vec<ObjectResource>,
+    #[serde(default)]
+    prefixes: Vec<String>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectResource {
+    name: String,
+    size: Option<String>,
+    updated: Option<String>,
+}
+
+/// Origin serving objects from a single GCS bucket.
+pub struct GoogleCloudStorageOrigin {
+    client: Arc<GoogleCloudStorageClient>,
+    bucket: String,
+}
+
+impl GoogleCloudStorageOrigin {
+    pub fn new(client: Arc<GoogleCloudStorageClient>, bucket: String) -> Self {
+        Self {
+            client,
+            bucket,
+        }
+    }
+}
+
+#[async_trait]
+impl Origin for GoogleCloudStorageOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.client.get_object(&self.bucket, object, options).await
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.client.head_object(&self.bucket, object, options).await
+    }
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        self.client.list_objects(&self.bucket, prefix, delimiter, page_token).await
+    }
+
+    async fn put_object(&self, object: &str, body: BodyStream, options: &PutObjectOptions) -> Result<(), OriginError> {
+        self.client.put_object(&self.bucket, object, body, options).await
+    }
+
+    async fn delete_object(&self, object: &str) -> Result<(), OriginError> {
+        self.client.delete_object(&self.bucket, object).await
+    }
+}
+
+async fn read_body(mut body: BodyStream, capacity: usize) -> Result<Vec<u8>, OriginError> {
+    let mut result = Vec::with_capacity(capacity);
+    while let Some(chunk) = body.next().await {
+        result.extend_from_slice(&chunk.map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })?);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::time::Instant;
+    use futures::future::join_all;
+    use hyper::{Body, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+
+    const LATENCY: Duration = Duration::from_millis(300);
+    const PARALLEL_REQUESTS: usize = 8;
+
+    /// Serves every request after a fixed delay, standing in for a slow storage api.
+    fn slow_storage() -> String {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                tokio::time::delay_for(LATENCY).await;
+                Ok::<_, Infallible>(Response::new(Body::from("object")))
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn fetches_objects_concurrently() {
+        let endpoint = slow_storage();
+        let client = GoogleCloudStorageClient::new(
+            TokenSource::Anonymous,
+            String::new(),
+            Some(endpoint),
+            Some(PARALLEL_REQUESTS),
+            None,
+        ).unwrap();
+
+        let started = Instant::now();
+        let results = join_all((0..PARALLEL_REQUESTS).map(|i| {
+            let object = format!("object-{}", i);
+            let client = &client;
+            async move { client.get_object("bucket", &object, &GetObjectOptions::default()).await }
+        })).await;
+        let elapsed = started.elapsed();
+
+        for result in results {
+            assert_eq!(result.unwrap().status, StatusCode::OK);
+        }
+        // serialized requests would take PARALLEL_REQUESTS times the latency
+        assert!(elapsed < LATENCY * 3, "parallel requests took {:?}", elapsed);
+    }
+
+    /// Sends response headers right away, then the body in two chunks `LATENCY` apart.
+    fn slowly_streaming_storage() -> String {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                let chunks = futures::stream::iter(vec!["first ", "second"]).then(|chunk| async move {
+                    tokio::time::delay_for(LATENCY).await;
+                    Ok::<_, Infallible>(chunk)
+                });
+                Ok::<_, Infallible>(Response::new(Body::wrap_stream(chunks)))
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        endpoint
+    }
+
+    fn client(endpoint: String, request_timeout: Duration) -> GoogleCloudStorageClient {
+        GoogleCloudStorageClient::new(TokenSource::Anonymous, String::new(), Some(endpoint), None, Some(request_timeout)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_for_response_headers() {
+        let client = client(slow_storage(), LATENCY / 3);
+
+        match client.get_object("bucket", "object", &GetObjectOptions::default()).await {
+            Err(OriginError::Timeout) => {},
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_bodies_longer_than_the_request_timeout() {
+        let client = client(slowly_streaming_storage(), LATENCY / 3);
+
+        let result = client.get_object("bucket", "object", &GetObjectOptions::default()).await.unwrap();
+        let body = hyper::body::to_bytes(Body::from(result.body)).await.unwrap();
+        assert_eq!(&body[..], b"first second");
+    }
+
+    /// Stands in for the resumable upload api, recording the `Content-Range` of every uploaded chunk.
+    /// Chunks are acknowledged with a 308 and the persisted range, `lost_bytes` short of what was sent.
+    fn upload_storage(lost_bytes: u64) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let content_ranges = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let persisted = Arc::new(std::sync::Mutex::new(0u64));
+
+        let recorded = content_ranges.clone();
+        let make_service = make_service_fn(move |_| {
+            let content_ranges = recorded.clone();
+            let persisted = persisted.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let content_ranges = content_ranges.clone();
+                    let persisted = persisted.clone();
+                    async move {
+                        if req.method() == hyper::Method::POST {
+                            let location = format!("http://{}/session", req.headers()["host"].to_str().unwrap());
+                            return Ok::<_, Infallible>(Response::builder().header("location", location).body(Body::empty()).unwrap());
+                        }
+
+                        let content_range = req.headers()["content-range"].to_str().unwrap().to_string();
+                        let len = hyper::body::to_bytes(req.into_body()).await.unwrap().len() as u64;
+                        content_ranges.lock().unwrap().push(content_range.clone());
+                        if !content_range.ends_with("/*") {
+                            return Ok(Response::new(Body::empty()));
+                        }
+
+                        let mut persisted = persisted.lock().unwrap();
+                        *persisted += len - lost_bytes;
+                        Ok(Response::builder()
+                            .status(StatusCode::PERMANENT_REDIRECT)
+                            .header("range", format!("bytes=0-{}", *persisted - 1))
+                            .body(Body::empty())
+                            .unwrap())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (endpoint, content_ranges)
+    }
+
+    /// Uploads `len` bytes in pieces of 1MiB, returning the `Content-Range` of every chunk.
+    async fn upload(len: usize, lost_bytes: u64) -> (Result<(), OriginError>, Vec<String>) {
+        let (endpoint, content_ranges) = upload_storage(lost_bytes);
+        let client = client(endpoint.clone(), LATENCY * 10);
+        let pieces = (0..len).step_by(1024 * 1024)
+            .map(|start| Ok(bytes::Bytes::from(vec![0u8; (len - start).min(1024 * 1024)])))
+            .collect::<Vec<_>>();
+
+        let url = format!("{}/upload", endpoint);
+        let result = client.resumable_upload(&url, "object", "text/plain", futures::stream::iter(pieces).boxed(), None).await;
+        let content_ranges = content_ranges.lock().unwrap().clone();
+        (result, content_ranges)
+    }
+
+    #[tokio::test]
+    async fn uploads_in_chunks() {
+        let (result, content_ranges) = upload(2 * UPLOAD_CHUNK_SIZE + 10, 0).await;
+
+        assert!(result.is_ok());
+        assert_eq!(content_ranges, vec![
+            "bytes 0-8388607/*",
+            "bytes 8388608-16777215/*",
+            "bytes 16777216-16777225/16777226",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn sends_the_total_size_with_the_last_full_chunk() {
+        let (result, content_ranges) = upload(2 * UPLOAD_CHUNK_SIZE, 0).await;
+
+        assert!(result.is_ok());
+        assert_eq!(content_ranges, vec!["bytes 0-8388607/*", "bytes 8388608-16777215/16777216"]);
+    }
+
+    #[tokio::test]
+    async fn finalizes_empty_uploads_without_data() {
+        let (result, content_ranges) = upload(0, 0).await;
+
+        assert!(result.is_ok());
+        assert_eq!(content_ranges, vec!["bytes */0"]);
+    }
+
+    #[tokio::test]
+    async fn fails_when_a_chunk_is_not_fully_persisted() {
+        let (result, content_ranges) = upload(2 * UPLOAD_CHUNK_SIZE, 1).await;
+
+        match result {
+            Err(OriginError::FailedToParseResponse { .. }) => {},
+            _ => panic!("expected the upload to fail"),
+        }
+        assert_eq!(content_ranges, vec!["bytes 0-8388607/*"]);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs, env::var, sync::Mutex, path::PathBuf};
+use std::time::{Duration, Instant};
+use yup_oauth2::{ServiceAccountAuthenticator, ServiceAccountKey};
+use yup_oauth2::authenticator::{Authenticator, DefaultHyperClient, HyperClientBuilder};
+use crate::config::CredentialsConfiguration;
+use crate::origin::object::OriginError;
+
+const DEFAULT_METADATA_SERVER_URL: &str = "http://metadata.google.internal";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+// cached tokens are refreshed this long before they actually expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Source of access tokens for requests to cloud storage.
+pub enum TokenSource {
+    ServiceAccount(Box<Authenticator<<DefaultHyperClient as HyperClientBuilder>::Connector>>),
+    AuthorizedUser {
+        client: reqwest::Client,
+        credentials: AuthorizedUserCredentials,
+        token: CachedToken,
+    },
+    MetadataServer {
+        client: reqwest::Client,
+        url: String,
+        token: CachedToken,
+    },
+    Anonymous,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizedUserCredentials {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    token_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Default)]
+pub struct CachedToken {
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl CachedToken {
+
+    fn get(&self) -> Option<String> {
+        let token = self.token.lock().unwrap();
+        match &*token {
+            Some((token, expires_at)) if Instant::now() + TOKEN_EXPIRY_MARGIN < *expires_at => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&self, res: &TokenResponse) {
+        let expires_at = Instant::now() + Duration::from_secs(res.expires_in);
+        *self.token.lock().unwrap() = Some((res.access_token.clone(), expires_at));
+    }
+}
+
+impl TokenSource {
+
+    pub async fn new(config: &CredentialsConfiguration) -> Result<Self, OriginError> {
+        match config.authentication_type() {
+            "service_account" => Self::service_account(&service_account_key(config)?).await,
+            "application_default" => Self::application_default(config).await,
+            "metadata_server" => Ok(Self::metadata_server(config)),
+            "anonymous" => {
+                info!("using anonymous access to cloud storage");
+                Ok(TokenSource::Anonymous)
+            },
+            other => Err(OriginError::FailedToCreateClient { reason: format!("unknown authentication type: {}", other) }),
+        }
+    }
+
+    async fn service_account(service_account_key: &str) -> Result<Self, OriginError> {
+        let service_account_key: ServiceAccountKey = serde_json::from_str(service_account_key)
+            .map_err(|source| OriginError::FailedToReadAccountKey { details: format!("{}", source) })?;
+
+        let authenticator = ServiceAccountAuthenticator::builder(service_account_key)
+            .build().await.map_err(|source| OriginError::FailedToAuthToServiceAccount { source })?;
+
+        Ok(TokenSource::ServiceAccount(Box::new(authenticator)))
+    }
+
+    /// Uses the credentials file if one can be found (either a service account key or user credentials
+    /// created by `gcloud auth application-default login`), and the metadata server otherwise.
+    async fn application_default(config: &CredentialsConfiguration) -> Result<Self, OriginError> {
+        let path = match application_default_credentials_file(config) {
+            Some(v) => v,
+            None => {
+                info!("application default credentials file not found, using metadata server");
+                return Ok(Self::metadata_server(config));
+            }
+        };
+
+        let credentials = fs::read_to_string(&path)
+            .map_err(|err| OriginError::FailedToReadAccountKey { details: format!("{}: {}", path.display(), err) })?;
+        let credentials_type = serde_json::from_str::<serde_json::Value>(&credentials)
+            .map_err(|err| OriginError::FailedToReadAccountKey { details: format!("{}", err) })?
+            .get("type")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string());
+
+        match credentials_type.as_deref() {
+            Some("service_account") => Self::service_account(&credentials).await,
+            Some("authorized_user") => Ok(TokenSource::AuthorizedUser {
+                client: reqwest::Client::new(),
+                credentials: serde_json::from_str(&credentials)
+                    .map_err(|err| OriginError::FailedToReadAccountKey { details: format!("{}", err) })?,
+                token: CachedToken::default(),
+            }),
+            other => Err(OriginError::FailedToReadAccountKey {
+                details: format!("unsupported credentials type: {}", other.unwrap_or("none"))
+            }),
+        }
+    }
+
+    fn metadata_server(config: &CredentialsConfiguration) -> Self {
+        let url = config.metadata_server_url.clone()
+            .or_else(|| var("GCE_METADATA_HOST").ok().map(|v| format!("http://{}", v)))
+            .unwrap_or_else(|| DEFAULT_METADATA_SERVER_URL.to_string());
+
+        TokenSource::MetadataServer {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token: CachedToken::default(),
+        }
+    }
+
+    /// Returns an access token for the given scopes, or `None` for anonymous access.
+    pub async fn token(&self, scopes: &[&str]) -> Result<Option<String>, OriginError> {
+        match self {
+            TokenSource::ServiceAccount(authenticator) => Ok(Some(authenticator.token(scopes).await?.as_str().to_string())),
+            TokenSource::AuthorizedUser { client, credentials, token } => {
+                if let Some(v) = token.get() {
+                    return Ok(Some(v));
+                }
+
+                let res = client.post(credentials.token_uri.as_deref().unwrap_or(DEFAULT_TOKEN_URI))
+                    .form(&[
+                        ("client_id", credentials.client_id.as_str()),
+                        ("client_secret", credentials.client_secret.as_str()),
+                        ("refresh_token", credentials.refresh_token.as_str()),
+                        ("grant_type", "refresh_token"),
+                    ])
+                    .send()
+                    .await?;
+
+                Ok(Some(read_token_response(res, token).await?))
+            },
+            TokenSource::MetadataServer { client, url, token } => {
+                if let Some(v) = token.get() {
+                    return Ok(Some(v));
+                }
+
+                let res = client.get(&format!("{}/computeMetadata/v1/instance/service-accounts/default/token", url))
+                    .query(&[("scopes", scopes.join(","))])
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?;
+
+                Ok(Some(read_token_response(res, token).await?))
+            },
+            TokenSource::Anonymous => Ok(None),
+        }
+    }
+}
+
+async fn read_token_response(res: reqwest::Response, token: &CachedToken) -> Result<String, OriginError> {
+    if !res.status().is_success() {
+        return Err(OriginError::UnexpectedStatus { status: res.status() });
+    }
+
+    let res: TokenResponse = serde_json::from_slice(&res.bytes().await?)
+        .map_err(|err| OriginError::FailedToParseResponse { details: format!("{}", err) })?;
+    token.set(&res);
+
+    Ok(res.access_token)
+}
+
+fn service_account_key(config: &CredentialsConfiguration) -> Result<String, OriginError> {
+    match &config.service_account_key {
+        Some(v) => Ok(v.to_string()),
+        None => {
+            let file_name = config.service_account_key_file.clone().unwrap_or_else(get_service_account_key_file_name);
+            fs::read_to_string(&file_name)
+                .map_err(|err| OriginError::FailedToReadAccountKey { details: format!("{}: {}", file_name, err) })
+        }
+    }
+}
+
+fn get_service_account_key_file_name() -> String {
+    var("SERVICE_ACCOUNT_KEY_FILE").unwrap_or("service_account_key.json".into())
+}
+
+fn application_default_credentials_file(config: &CredentialsConfiguration) -> Option<PathBuf> {
+    if let Some(v) = config.credentials_file.as_ref().cloned().or_else(|| var("GOOGLE_APPLICATION_CREDENTIALS").ok()) {
+        return Some(PathBuf::from(v));
+    }
+
+    let path = PathBuf::from(var("HOME").ok()?).join(".config/gcloud/application_default_credentials.json");
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
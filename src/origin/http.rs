@@ -0,0 +1,69 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use reqwest::redirect::Policy;
+use crate::origin::object::{encode_object_path, OriginError, GetObjectResult, GetObjectOptions, ListObjectsResult};
+use crate::origin::origin::{client_builder, send_request, Origin};
+
+/// Origin relaying requests to an arbitrary http(s) server.
+pub struct HttpOrigin {
+    reqwest_client: reqwest::Client,
+    request_timeout: Option<Duration>,
+    upstream: String,
+}
+
+impl HttpOrigin {
+
+    pub fn new(upstream: String, connection_pool_size: Option<usize>, request_timeout: Option<Duration>) -> Result<Self, OriginError> {
+        // redirects are relayed to the client instead of being followed
+        let reqwest_client = client_builder(connection_pool_size, request_timeout)
+            .redirect(Policy::none())
+            .build()?;
+
+        Ok(Self {
+            reqwest_client,
+            request_timeout,
+            upstream: upstream.trim_end_matches('/').to_string(),
+        })
+    }
+
+    async fn request_object(&self, method: reqwest::Method, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        if options.generation.is_some() {
+            return Err(OriginError::OperationNotSupported { operation: "object generations".to_string() });
+        }
+
+        let url = format!("{}/{}", self.upstream, encode_object_path(object)?);
+
+        let mut req = self.reqwest_client.request(method, &url);
+
+        if let Some(range) = &options.range {
+            req = req.header("Range", range);
+        }
+
+        if let Some(if_none_match) = &options.if_none_match {
+            req = req.header("If-None-Match", if_none_match);
+        }
+
+        if let Some(if_modified_since) = &options.if_modified_since {
+            req = req.header("If-Modified-Since", if_modified_since);
+        }
+
+        // statuses are relayed as is, this origin is a plain http proxy
+        GetObjectResult::new(send_request(req, self.request_timeout).await?).await
+    }
+}
+
+#[async_trait]
+impl Origin for HttpOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(reqwest::Method::GET, object, options).await
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.request_object(reqwest::Method::HEAD, object, options).await
+    }
+
+    async fn list_objects(&self, _prefix: &str, _delimiter: Option<&str>, _page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        Err(OriginError::OperationNotSupported { operation: "list objects".to_string() })
+    }
+}
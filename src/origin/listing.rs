@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use hyper::StatusCode;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use crate::origin::object::{OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ListObjectsResult, BodyStream, ObjectInfo, ObjectBody};
+use crate::origin::origin::Origin;
+
+// listings are not paginated for the client, so very large prefixes are cut off
+const MAX_LISTING_PAGES: usize = 100;
+
+const HREF_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'/');
+
+#[derive(Clone, Copy)]
+pub enum ListingFormat {
+    Html,
+    Json,
+}
+
+impl ListingFormat {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(ListingFormat::Html),
+            "json" => Some(ListingFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Serves a generated directory index when the index object of a directory does not exist.
+pub struct DirectoryListingOrigin {
+    origin: Arc<dyn Origin>,
+    // prefix of the bucket that is served as the root directory
+    root: String,
+    index: String,
+    format: ListingFormat,
+}
+
+impl DirectoryListingOrigin {
+
+    pub fn new(origin: Arc<dyn Origin>, root: String, index: String, format: ListingFormat) -> Self {
+        Self {
+            origin,
+            root,
+            index,
+            format,
+        }
+    }
+
+    /// Directory prefix for requests of directory index objects.
+    fn directory_prefix<'a>(&self, object: &'a str) -> Option<&'a str> {
+        let prefix = object.strip_suffix(self.index.as_str())?;
+        if prefix.is_empty() || prefix.ends_with('/') {
+            Some(prefix)
+        } else {
+            None
+        }
+    }
+
+    async fn listing(&self, prefix: &str) -> Result<GetObjectResult, OriginError> {
+        let prefix = percent_decode_str(prefix).decode_utf8_lossy().to_string();
+
+        let mut listing = ListObjectsResult {
+            objects: Vec::new(),
+            prefixes: Vec::new(),
+            next_page_token: None,
+        };
+
+        for _ in 0..MAX_LISTING_PAGES {
+            let page = self.origin.list_objects(&prefix, Some("/"), listing.next_page_token.as_deref()).await?;
+            listing.objects.extend(page.objects);
+            listing.prefixes.extend(page.prefixes);
+            listing.next_page_token = page.next_page_token;
+
+            if listing.next_page_token.is_none() {
+                break;
+            }
+        }
+
+        // placeholder objects some tools create for directories are not listed
+        listing.objects.retain(|v| v.name != prefix);
+
+        let is_root = prefix == self.root;
+        if !is_root && listing.objects.is_empty() && listing.prefixes.is_empty() {
+            return Err(OriginError::ObjectNotFound);
+        }
+
+        let path = relative_name(&self.root, &prefix);
+        let (content_type, body) = match self.format {
+            ListingFormat::Html => ("text/html; charset=utf-8", render_html(&prefix, path, is_root, &listing)),
+            ListingFormat::Json => ("application/json", render_json(&prefix, path, &listing)?),
+        };
+
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), content_type.to_string());
+        headers.insert("content-length".to_string(), body.len().to_string());
+
+        Ok(GetObjectResult {
+            status: StatusCode::OK,
+            body: ObjectBody::Bytes(body),
+            headers,
+        })
+    }
+}
+
+#[async_trait]
+impl Origin for DirectoryListingOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        match (self.origin.get_object(object, options).await, self.directory_prefix(object)) {
+            (Err(OriginError::ObjectNotFound), Some(prefix)) => self.listing(prefix).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        match (self.origin.head_object(object, options).await, self.directory_prefix(object)) {
+            (Err(OriginError::ObjectNotFound), Some(prefix)) => self.listing(prefix).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        self.origin.list_objects(prefix, delimiter, page_token).await
+    }
+
+    async fn put_object(&self, object: &str, body: BodyStream, options: &PutObjectOptions) -> Result<(), OriginError> {
+        self.origin.put_object(object, body, options).await
+    }
+
+    async fn delete_object(&self, object: &str) -> Result<(), OriginError> {
+        self.origin.delete_object(object).await
+    }
+}
+
+fn render_html(prefix: &str, path: &str, is_root: bool, listing: &ListObjectsResult) -> Vec<u8> {
+    let title = format!("Index of /{}", html_escape(path));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<table>\n\
+        <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+        title
+    );
+
+    if !is_root {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for directory in &listing.prefixes {
+        let name = relative_name(prefix, directory);
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>-</td><td></td></tr>\n",
+            utf8_percent_encode(name, HREF_ENCODE_SET),
+            html_escape(name)
+        ));
+    }
+
+    for object in &listing.objects {
+        let name = relative_name(prefix, &object.name);
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            utf8_percent_encode(name, HREF_ENCODE_SET),
+            html_escape(name),
+            object.size,
+            html_escape(object.updated.as_deref().unwrap_or(""))
+        ));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html.into_bytes()
+}
+
+#[derive(Serialize)]
+struct JsonListing<'a> {
+    prefix: &'a str,
+    directories: Vec<&'a str>,
+    objects: Vec<JsonListingObject<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonListingObject<'a> {
+    name: &'a str,
+    size: u64,
+    updated: Option<&'a str>,
+}
+
+fn render_json(prefix: &str, path: &str, listing: &ListObjectsResult) -> Result<Vec<u8>, OriginError> {
+    let listing = JsonListing {
+        prefix: path,
+        directories: listing.prefixes.iter().map(|v| relative_name(prefix, v)).collect(),
+        objects: listing.objects.iter().map(|v: &ObjectInfo| JsonListingObject {
+            name: relative_name(prefix, &v.name),
+            size: v.size,
+            updated: v.updated.as_deref(),
+        }).collect(),
+    };
+
+    serde_json::to_vec(&listing).map_err(|err| OriginError::FailedToParseResponse { details: format!("{}", err) })
+}
+
+fn relative_name<'a>(prefix: &str, name: &'a str) -> &'a str {
+    name.strip_prefix(prefix).unwrap_or(name)
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,10 @@
+pub mod origin;
+pub mod object;
+pub mod gcs;
+pub mod gcs_auth;
+pub mod s3;
+pub mod filesystem;
+pub mod http;
+pub mod retry;
+pub mod circuit_breaker;
+pub mod listing;
\ No newline at end of file
@@ -0,0 +1,281 @@
+use custom_error::custom_error;
+use std::io::ErrorKind;
+use std::collections::HashMap;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use bytes::Bytes;
+use hyper::{Body, StatusCode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+// connection-specific headers which should not be relayed to the client
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade"
+];
+
+/// Everything except unreserved characters is encoded, in object path segments as in sigv4 canonical requests.
+pub const UNRESERVED_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+custom_error!{pub OriginError
+    FailedToReadAccountKey{details: String} = "failed to read service account key: {details}",
+    FailedToAuthToServiceAccount{source: std::io::Error} = "failed to auth to service account: {source}",
+    FailedToCreateClient{reason: String} = "failed to create origin client: {reason}",
+    SigningFailed{source: openssl::error::ErrorStack} = "failed to sign request: {source}",
+    OAuthError{source: yup_oauth2::error::Error} = "oauth failed: {source}",
+    RequestFailed{source: reqwest::Error} = "request failed: {source}",
+    FailedToReadBody{details: String} = "failed to read object body: {details}",
+    FailedToParseResponse{details: String} = "failed to parse origin response: {details}",
+    UnexpectedStatus{status: StatusCode} = "unexpected response status: {status}",
+    Unauthorized = "origin rejected credentials",
+    Forbidden = "access to object is forbidden",
+    PreconditionFailed = "precondition failed",
+    RateLimited = "origin rate limit exceeded",
+    ServerError{status: StatusCode} = "origin server error: {status}",
+    Timeout = "origin request timed out",
+    InvalidObjectName{name: String} = "invalid object name: {name}",
+    OperationNotSupported{operation: String} = "operation not supported by origin: {operation}",
+    CircuitOpen = "origin circuit breaker is open",
+    ObjectNotFound = "object not found",
+    RangeNotSatisfiable{len: Option<u64>} = @{ "requested range not satisfiable" }
+}
+
+impl From<OriginError> for std::io::Error {
+
+    fn from(err: OriginError) -> Self {
+        std::io::Error::new(ErrorKind::Other, format!("origin error: {}", err))
+    }
+}
+
+impl OriginError {
+
+    /// Error for a non-successful response status of a storage api.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => OriginError::Unauthorized,
+            StatusCode::FORBIDDEN => OriginError::Forbidden,
+            StatusCode::NOT_FOUND => OriginError::ObjectNotFound,
+            StatusCode::PRECONDITION_FAILED => OriginError::PreconditionFailed,
+            StatusCode::RANGE_NOT_SATISFIABLE => OriginError::RangeNotSatisfiable { len: None },
+            StatusCode::TOO_MANY_REQUESTS => OriginError::RateLimited,
+            status if status.is_server_error() => OriginError::ServerError { status },
+            status => OriginError::UnexpectedStatus { status },
+        }
+    }
+
+    /// Error for a 416 response, with the object length from its `Content-Range: bytes */len` header.
+    pub fn from_range_not_satisfiable(res: &reqwest::Response) -> Self {
+        let len = res.headers().get("content-range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes */"))
+            .and_then(|v| v.trim().parse().ok());
+
+        OriginError::RangeNotSatisfiable { len }
+    }
+
+    pub fn from_request_error(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            OriginError::Timeout
+        } else {
+            OriginError::RequestFailed { source: err }
+        }
+    }
+
+    /// Whether the request may succeed if repeated: connection errors, server errors and throttling.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            OriginError::RequestFailed { .. }
+                | OriginError::Timeout
+                | OriginError::RateLimited
+                | OriginError::ServerError { .. } => true,
+            OriginError::UnexpectedStatus { status } => is_transient_status(*status),
+            _ => false,
+        }
+    }
+
+    /// Label for the origin errors metric.
+    pub fn class(&self) -> &'static str {
+        match self {
+            OriginError::ObjectNotFound => "not_found",
+            OriginError::RangeNotSatisfiable { .. } => "range_not_satisfiable",
+            OriginError::InvalidObjectName { .. } => "invalid_object_name",
+            OriginError::Unauthorized => "unauthorized",
+            OriginError::Forbidden => "forbidden",
+            OriginError::PreconditionFailed => "precondition_failed",
+            OriginError::RateLimited => "rate_limited",
+            OriginError::ServerError { .. } => "server_error",
+            OriginError::Timeout => "timeout",
+            OriginError::CircuitOpen => "circuit_open",
+            OriginError::RequestFailed { .. } => "request_failed",
+            OriginError::UnexpectedStatus { .. } => "unexpected_status",
+            _ => "internal",
+        }
+    }
+}
+
+/// Whether a response with this status may succeed if repeated: server errors and throttling.
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Decodes a percent-encoded object name. Names with `.` or `..` segments are rejected, as they would be
+/// normalized away in origin urls and could address objects outside of the bucket.
+pub fn decode_object_name(object: &str) -> Result<String, OriginError> {
+    let name = percent_decode_str(object).decode_utf8_lossy();
+
+    if name.split('/').any(|v| v == "." || v == "..") {
+        return Err(OriginError::InvalidObjectName { name: name.to_string() });
+    }
+
+    Ok(name.into_owned())
+}
+
+/// Url path of an object, relative to its bucket: every segment of the decoded name is encoded on its own,
+/// so the name cannot change the rest of the url.
+pub fn encode_object_path(object: &str) -> Result<String, OriginError> {
+    Ok(decode_object_name(object)?.split('/')
+        .map(|v| utf8_percent_encode(v, UNRESERVED_ENCODE_SET).to_string())
+        .collect::<Vec<String>>()
+        .join("/"))
+}
+
+pub type BodyStream = BoxStream<'static, Result<Bytes, Box<dyn std::error::Error + Send + Sync>>>;
+
+pub enum ObjectBody {
+    Bytes(Vec<u8>),
+    Stream(BodyStream),
+}
+
+impl From<ObjectBody> for Body {
+
+    fn from(body: ObjectBody) -> Self {
+        match body {
+            ObjectBody::Bytes(v) => Body::from(v),
+            ObjectBody::Stream(v) => Body::wrap_stream(v),
+        }
+    }
+}
+
+pub struct GetObjectResult {
+    pub status: StatusCode,
+    pub body: ObjectBody,
+    pub headers: HashMap<String, String>
+}
+
+pub struct ListObjectsResult {
+    pub objects: Vec<ObjectInfo>,
+    pub prefixes: Vec<String>,
+    pub next_page_token: Option<String>,
+}
+
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    pub updated: Option<String>,
+}
+
+#[derive(Default, Clone)]
+pub struct GetObjectOptions {
+    pub range: Option<String>,
+    pub generation: Option<u64>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    // checked against partial responses by the proxy itself, origins do not see it
+    pub if_range: Option<String>,
+}
+
+#[derive(Default, Clone)]
+pub struct PutObjectOptions {
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+}
+
+impl GetObjectResult {
+
+    /// Same as `new`, but every status other than 2xx and 304 is turned into an error.
+    pub async fn from_storage_response(res: reqwest::Response) -> Result<Self, OriginError> {
+        if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Err(OriginError::from_range_not_satisfiable(&res));
+        }
+
+        if !res.status().is_success() && res.status() != StatusCode::NOT_MODIFIED {
+            return Err(OriginError::from_status(res.status()));
+        }
+
+        Self::new(res).await
+    }
+
+    pub async fn new(res: reqwest::Response) -> Result<Self, OriginError> {
+        if res.status() == 404 {
+            return Err(OriginError::ObjectNotFound)
+        }
+
+        if res.status() == 416 {
+            return Err(OriginError::from_range_not_satisfiable(&res))
+        }
+
+        let status = res.status();
+
+        let headers = &res.headers().clone();
+        let headers = headers.into_iter()
+            .filter(|v| !HOP_BY_HOP_HEADERS.contains(&v.0.as_str()))
+            .map(|v| (v.0.clone().to_string(), v.1.to_str().unwrap_or("").to_string()))
+            .collect::<HashMap<String, String>>().clone();
+
+        let body = ObjectBody::Stream(res.bytes_stream().map_err(|err| err.into()).boxed());
+
+        Ok(GetObjectResult {
+            status,
+            body,
+            headers,
+        })
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            ObjectBody::Bytes(v) => Some(v.len() as u64),
+            ObjectBody::Stream(_) => self.headers.get("content-length").and_then(|v| v.parse().ok()),
+        }
+    }
+
+    /// Reads the whole body into memory. Only meant for objects small enough to be cached.
+    pub async fn into_bytes(self) -> Result<(Vec<u8>, HashMap<String, String>), OriginError> {
+        let capacity = self.content_length().unwrap_or(0) as usize;
+        let body = match self.body {
+            ObjectBody::Bytes(v) => v,
+            ObjectBody::Stream(mut stream) => {
+                let mut body = Vec::with_capacity(capacity);
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk.map_err(|err| OriginError::FailedToReadBody { details: format!("{}", err) })?;
+                    body.extend_from_slice(&chunk);
+                }
+                body
+            }
+        };
+
+        Ok((body, self.headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_object_names() {
+        assert_eq!(decode_object_name("docs/a%20b.txt").unwrap(), "docs/a b.txt");
+        assert_eq!(decode_object_name("a..b/.hidden").unwrap(), "a..b/.hidden");
+    }
+
+    #[test]
+    fn encodes_object_paths() {
+        assert_eq!(encode_object_path("docs/a%20b+c.txt").unwrap(), "docs/a%20b%2Bc.txt");
+        assert_eq!(encode_object_path("a?b#c").unwrap(), "a%3Fb%23c");
+        assert!(encode_object_path("docs/../../other").is_err());
+    }
+
+    #[test]
+    fn rejects_dot_segments() {
+        for name in &["../other/a", "docs/..", "docs/./a", "%2e%2e/other", "docs/%2E%2e/a", "docs%2f..%2fa"] {
+            assert!(matches!(decode_object_name(name), Err(OriginError::InvalidObjectName { .. })), "{}", name);
+        }
+        assert_eq!(decode_object_name("a..b/.well-known").unwrap(), "a..b/.well-known");
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use custom_error::custom_error;
+use crate::config::{self, Config};
+use crate::origin::object::{OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ListObjectsResult, BodyStream};
+use crate::origin::gcs::{GoogleCloudStorageClient, GoogleCloudStorageOrigin};
+use crate::origin::gcs_auth::TokenSource;
+use crate::origin::s3::S3Origin;
+use crate::origin::filesystem::FilesystemOrigin;
+use crate::origin::http::HttpOrigin;
+use crate::origin::retry::RetryingOrigin;
+use crate::origin::circuit_breaker::CircuitBreakerOrigin;
+use crate::origin::listing::{DirectoryListingOrigin, ListingFormat};
+
+custom_error!{pub OriginInstantiationError
+    MissingField { field_name: String } = "missing field: {field_name}",
+    NotImplemented { origin_type: String } = "origin not implemented: {origin_type}",
+    UnknownCredentials { name: String } = "credentials not found: {name}",
+    UnknownListingFormat { format: String } = "unknown directory listing format: {format}",
+    InvalidBucket { name: String, reason: String } = "failed to make origin for {name}: {reason}",
+    OriginError { source: OriginError } = "origin error: {source}"
+}
+
+impl From<OriginInstantiationError> for std::io::Error {
+
+    fn from(err: OriginInstantiationError) -> Self {
+        std::io::Error::other(format!("{}", err))
+    }
+}
+
+/// Storage objects are served from. Every configured bucket gets its own instance.
+#[async_trait]
+pub trait Origin: Send + Sync {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError>;
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError>;
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, page_token: Option<&str>) -> Result<ListObjectsResult, OriginError>;
+
+    /// Writes an object. Origins are read-only unless they override this.
+    async fn put_object(&self, _object: &str, _body: BodyStream, _options: &PutObjectOptions) -> Result<(), OriginError> {
+        Err(OriginError::OperationNotSupported { operation: "put object".to_string() })
+    }
+
+    async fn delete_object(&self, _object: &str) -> Result<(), OriginError> {
+        Err(OriginError::OperationNotSupported { operation: "delete object".to_string() })
+    }
+}
+
+/// Builder for the http client of an origin, with the configured connection pool size. Connecting to the
+/// origin fails after the request timeout.
+pub fn client_builder(connection_pool_size: Option<usize>, request_timeout: Option<Duration>) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder();
+    if let Some(connection_pool_size) = connection_pool_size {
+        builder = builder.pool_max_idle_per_host(connection_pool_size);
+    }
+    if let Some(request_timeout) = request_timeout {
+        builder = builder.connect_timeout(request_timeout);
+    }
+    builder
+}
+
+/// Sends a request to an origin, failing with `OriginError::Timeout` if the response headers take longer
+/// than `request_timeout`. Response bodies have no deadline: large objects are streamed as long as it takes.
+pub async fn send_request(req: reqwest::RequestBuilder, request_timeout: Option<Duration>) -> Result<reqwest::Response, OriginError> {
+    let res = match request_timeout {
+        Some(request_timeout) => tokio::time::timeout(request_timeout, req.send()).await
+            .map_err(|_| OriginError::Timeout)?,
+        None => req.send().await,
+    };
+    res.map_err(OriginError::from_request_error)
+}
+
+pub struct Origins {
+    origins: HashMap<String, Arc<dyn Origin>>
+}
+
+impl Origins {
+    /// Origins of all configured buckets. Any misconfigured bucket fails the whole configuration.
+    pub async fn new(config: &Config) -> Result<Self, OriginInstantiationError> {
+        let empty = HashMap::new();
+        let buckets = config.buckets.as_ref().unwrap_or(&empty);
+
+        // one client per credentials name used by gcs buckets, `None` being the top-level credentials
+        let mut gcs_clients = HashMap::new();
+        for bucket_config in buckets.values().filter(|v| v.origin_type() == "gcs") {
+            let credentials_name = bucket_config.credentials.as_deref();
+            if gcs_clients.contains_key(&credentials_name) {
+                continue;
+            }
+
+            let credentials = config.credentials_by_name(credentials_name)
+                .ok_or_else(|| OriginInstantiationError::UnknownCredentials { name: credentials_name.unwrap_or_default().to_string() })?;
+
+            let writable = buckets.values()
+                .any(|v| v.origin_type() == "gcs" && v.credentials.as_deref() == credentials_name && v.is_writable());
+
+            gcs_clients.insert(credentials_name, Arc::new(GoogleCloudStorageClient::new(
+                TokenSource::new(credentials).await?,
+                credentials.scope(writable),
+                config.storage_endpoint.clone(),
+                config.connection_pool_size,
+                config.request_timeout(),
+            )?));
+        }
+
+        let mut origins = HashMap::new();
+
+        for (name, bucket_config) in buckets {
+            let origin = Self::make_origin(bucket_config, &gcs_clients, config)
+                .and_then(|v| Self::with_directory_listing(Self::with_failure_handling(v, name, bucket_config), bucket_config))
+                .map_err(|err| OriginInstantiationError::InvalidBucket { name: name.clone(), reason: err.to_string() })?;
+            origins.insert(name.clone(), origin);
+        }
+
+        Ok(Origins {
+            origins
+        })
+    }
+
+    fn make_origin(
+        config: &config::BucketConfiguration,
+        gcs_clients: &HashMap<Option<&str>, Arc<GoogleCloudStorageClient>>,
+        global_config: &Config,
+    ) -> Result<Arc<dyn Origin>, OriginInstantiationError> {
+        let bucket = || config.bucket.clone().ok_or(OriginInstantiationError::MissingField { field_name: "bucket".to_string() });
+
+        match config.origin_type() {
+            "gcs" => match gcs_clients.get(&config.credentials.as_deref()) {
+                Some(client) => Ok(Arc::new(GoogleCloudStorageOrigin::new(client.clone(), bucket()?))),
+                None => Err(OriginInstantiationError::MissingField { field_name: "credentials".to_string() }),
+            },
+            "s3" => Ok(Arc::new(S3Origin::new(
+                bucket()?,
+                config.endpoint.clone(),
+                config.region.clone(),
+                config.access_key_id.clone(),
+                config.secret_access_key.clone(),
+                global_config.connection_pool_size,
+                global_config.request_timeout(),
+            )?)),
+            "filesystem" => match &config.directory {
+                Some(directory) => Ok(Arc::new(FilesystemOrigin::new(directory.clone()))),
+                None => Err(OriginInstantiationError::MissingField { field_name: "directory".to_string() }),
+            },
+            "http" => match &config.upstream {
+                Some(upstream) => Ok(Arc::new(HttpOrigin::new(upstream.clone(), global_config.connection_pool_size, global_config.request_timeout())?)),
+                None => Err(OriginInstantiationError::MissingField { field_name: "upstream".to_string() }),
+            },
+            origin_type => Err(OriginInstantiationError::NotImplemented { origin_type: origin_type.to_string() })
+        }
+    }
+
+    fn with_failure_handling(origin: Arc<dyn Origin>, name: &str, config: &config::BucketConfiguration) -> Arc<dyn Origin> {
+        let mut origin = origin;
+
+        if config.retry_max_attempts() > 1 {
+            origin = Arc::new(RetryingOrigin::new(
+                origin,
+                config.retry_max_attempts(),
+                config.retry_base_delay(),
+                config.retry_max_delay(),
+            ));
+        }
+
+        // breaker wraps retries, so it counts a failure per request that failed after all attempts
+        if let Some(threshold) = config.circuit_breaker_threshold {
+            origin = Arc::new(CircuitBreakerOrigin::new(
+                origin,
+                name.to_string(),
+                threshold,
+                config.circuit_breaker_reset_timeout(),
+            ));
+        }
+
+        origin
+    }
+
+    fn with_directory_listing(origin: Arc<dyn Origin>, config: &config::BucketConfiguration) -> Result<Arc<dyn Origin>, OriginInstantiationError> {
+        let format = match &config.directory_listing {
+            Some(v) => v,
+            None => return Ok(origin),
+        };
+
+        match ListingFormat::from_name(format) {
+            Some(format) => Ok(Arc::new(DirectoryListingOrigin::new(origin, config.prefix(), config.index().to_string(), format))),
+            None => Err(OriginInstantiationError::UnknownListingFormat { format: format.clone() }),
+        }
+    }
+
+    pub fn get_origin(&self, name: &str) -> Option<Arc<dyn Origin>> {
+        self.origins.get(name).cloned()
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use hyper::StatusCode;
+use prometheus::{Counter, register_counter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::origin::object::{is_transient_status, OriginError, GetObjectResult, GetObjectOptions, PutObjectOptions, ListObjectsResult, BodyStream};
+use crate::origin::origin::Origin;
+
+lazy_static! {
+    static ref ORIGIN_RETRIES_COUNTER: Counter = register_counter!(
+        "origin_retries",
+        "origin requests retried after a transient failure"
+    ).unwrap();
+}
+
+/// Retries transient origin failures with exponential backoff and full jitter. Reads and deletes are
+/// retried, uploads are not.
+pub struct RetryingOrigin {
+    origin: Arc<dyn Origin>,
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryingOrigin {
+
+    pub fn new(origin: Arc<dyn Origin>, max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            origin,
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay,
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max_delay = self.base_delay
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let mut random = [0u8; 8];
+        let random = match openssl::rand::rand_bytes(&mut random) {
+            Ok(_) => u64::from_le_bytes(random),
+            Err(_) => u64::MAX,
+        };
+
+        Duration::from_millis(random % (max_delay.as_millis() as u64 + 1))
+    }
+
+    async fn with_retries<T, F, Fut>(&self, request: F) -> Result<T, OriginError>
+        where F: Fn() -> Fut, Fut: std::future::Future<Output=Result<T, OriginError>>, T: OriginResponse {
+        let mut attempt = 0;
+
+        loop {
+            let result = request().await;
+            attempt += 1;
+
+            if attempt >= self.max_attempts || !is_transient_failure(&result) {
+                return result;
+            }
+
+            ORIGIN_RETRIES_COUNTER.inc();
+            match &result {
+                Ok(v) => warn!("retrying origin request after status {}", v.status()),
+                Err(err) => warn!("retrying origin request after error: {}", err),
+            }
+
+            tokio::time::delay_for(self.backoff(attempt - 1)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Origin for RetryingOrigin {
+
+    async fn get_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.with_retries(|| self.origin.get_object(object, options)).await
+    }
+
+    async fn head_object(&self, object: &str, options: &GetObjectOptions) -> Result<GetObjectResult, OriginError> {
+        self.with_retries(|| self.origin.head_object(object, options)).await
+    }
+
+    async fn list_objects(&self, prefix: &str, delimiter: Option<&str>, page_token: Option<&str>) -> Result<ListObjectsResult, OriginError> {
+        self.with_retries(|| self.origin.list_objects(prefix, delimiter, page_token)).await
+    }
+
+    // uploads are not retried: the body is a stream which can not be replayed
+    async fn put_object(&self, object: &str, body: BodyStream, options: &PutObjectOptions) -> Result<(), OriginError> {
+        self.origin.put_object(object, body, options).await
+    }
+
+    async fn delete_object(&self, object: &str) -> Result<(), OriginError> {
+        let attempts = AtomicU32::new(0);
+
+        self.with_retries(|| async {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+            match self.origin.delete_object(object).await {
+                // a failed attempt may still have deleted the object before the response was lost
+                Err(OriginError::ObjectNotFound) if attempt > 0 => Ok(()),
+                result => result,
+            }
+        }).await
+    }
+}
+
+pub trait OriginResponse {
+    fn status(&self) -> StatusCode;
+}
+
+impl OriginResponse for GetObjectResult {
+
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl OriginResponse for () {
+
+    fn status(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+impl OriginResponse for ListObjectsResult {
+
+    fn status(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// Whether the request failed in a way that may succeed if repeated: connection errors,
+/// server errors and throttling.
+pub fn is_transient_failure<T: OriginResponse>(result: &Result<T, OriginError>) -> bool {
+    match result {
+        Ok(v) => is_transient_status(v.status()),
+        Err(err) => err.is_transient(),
+    }
+}
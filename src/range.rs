@@ -0,0 +1,225 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// more ranges than this in a single request are ignored and the full object is served instead
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeSpec {
+    FromTo(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl RangeSpec {
+
+    pub fn resolve(&self, len: u64) -> Option<ByteRange> {
+        match *self {
+            RangeSpec::FromTo(start, end) if start < len => Some(ByteRange { start, end: end.min(len - 1) }),
+            RangeSpec::From(start) if start < len => Some(ByteRange { start, end: len - 1 }),
+            RangeSpec::Suffix(suffix_len) if suffix_len > 0 && len > 0 => Some(ByteRange {
+                start: len - suffix_len.min(len),
+                end: len - 1,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_header_value(self) -> String {
+        match self {
+            RangeSpec::FromTo(start, end) => format!("bytes={}-{}", start, end),
+            RangeSpec::From(start) => format!("bytes={}-", start),
+            RangeSpec::Suffix(suffix_len) => format!("bytes=-{}", suffix_len),
+        }
+    }
+}
+
+impl ByteRange {
+
+    pub fn content_range(&self, total_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total_len)
+    }
+}
+
+/// Parses a `Range` header value. Returns `None` for anything that is not a valid bytes range set,
+/// in which case the header should be ignored.
+pub fn parse_range_header(value: &str) -> Option<Vec<RangeSpec>> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return None;
+    }
+
+    let specs = value["bytes=".len()..].split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(parse_range_spec)
+        .collect::<Option<Vec<RangeSpec>>>()?;
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    Some(specs)
+}
+
+fn parse_range_spec(spec: &str) -> Option<RangeSpec> {
+    let mut parts = spec.splitn(2, '-');
+    let start = parts.next()?.trim();
+    let end = parts.next()?.trim();
+
+    if start.is_empty() {
+        return end.parse().ok().map(RangeSpec::Suffix);
+    }
+
+    let start: u64 = start.parse().ok()?;
+    if end.is_empty() {
+        return Some(RangeSpec::From(start));
+    }
+
+    let end: u64 = end.parse().ok()?;
+    if end < start {
+        return None;
+    }
+
+    Some(RangeSpec::FromTo(start, end))
+}
+
+/// Satisfiable ranges of an object with the given length, in order, with overlapping and adjacent ranges merged.
+pub fn resolve_ranges(specs: &[RangeSpec], len: u64) -> Vec<ByteRange> {
+    let mut ranges = specs.iter().filter_map(|v| v.resolve(len)).collect::<Vec<ByteRange>>();
+    ranges.sort_by_key(|v| v.start);
+
+    let mut result: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match result.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => result.push(range),
+        }
+    }
+
+    result
+}
+
+/// Total length of the satisfiable ranges as requested, before merging. Requests asking for more than
+/// the whole object are served the full object instead.
+pub fn requested_length(specs: &[RangeSpec], len: u64) -> u64 {
+    specs.iter()
+        .filter_map(|v| v.resolve(len))
+        .map(|v| v.end - v.start + 1)
+        .fold(0, u64::saturating_add)
+}
+
+pub fn multipart_boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|v| v.as_nanos())
+        .unwrap_or(0);
+    format!("cloud_storage_proxy_{:x}", nanos)
+}
+
+/// Builds a `multipart/byteranges` body out of the given ranges of `body`.
+pub fn multipart_body(body: &[u8], ranges: &[ByteRange], content_type: Option<&str>, boundary: &str) -> Vec<u8> {
+    let total_len = body.len() as u64;
+    let mut result = Vec::new();
+
+    for range in ranges {
+        result.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = content_type {
+            result.extend_from_slice(format!("content-type: {}\r\n", content_type).as_bytes());
+        }
+        result.extend_from_slice(format!("content-range: {}\r\n\r\n", range.content_range(total_len)).as_bytes());
+        result.extend_from_slice(&body[range.start as usize..=range.end as usize]);
+    }
+
+    result.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_range_sets() {
+        assert_eq!(parse_range_header("bytes=0-99"), Some(vec![RangeSpec::FromTo(0, 99)]));
+        assert_eq!(parse_range_header("bytes=100-"), Some(vec![RangeSpec::From(100)]));
+        assert_eq!(parse_range_header("bytes=-100"), Some(vec![RangeSpec::Suffix(100)]));
+        assert_eq!(
+            parse_range_header("bytes=0-0, -1"),
+            Some(vec![RangeSpec::FromTo(0, 0), RangeSpec::Suffix(1)])
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_range_headers() {
+        assert_eq!(parse_range_header("items=0-10"), None);
+        assert_eq!(parse_range_header("bytes="), None);
+        assert_eq!(parse_range_header("bytes=10-5"), None);
+        assert_eq!(parse_range_header("bytes=a-b"), None);
+        assert_eq!(parse_range_header("bytes=5"), None);
+    }
+
+    #[test]
+    fn resolves_suffix_ranges() {
+        assert_eq!(RangeSpec::Suffix(10).resolve(100), Some(ByteRange { start: 90, end: 99 }));
+        // a suffix longer than the object selects all of it
+        assert_eq!(RangeSpec::Suffix(500).resolve(100), Some(ByteRange { start: 0, end: 99 }));
+        assert_eq!(RangeSpec::Suffix(0).resolve(100), None);
+    }
+
+    #[test]
+    fn clamps_ranges_to_object_length() {
+        assert_eq!(RangeSpec::FromTo(90, 200).resolve(100), Some(ByteRange { start: 90, end: 99 }));
+        assert_eq!(RangeSpec::From(99).resolve(100), Some(ByteRange { start: 99, end: 99 }));
+        assert_eq!(RangeSpec::From(100).resolve(100), None);
+        assert_eq!(RangeSpec::FromTo(100, 200).resolve(100), None);
+    }
+
+    #[test]
+    fn nothing_is_satisfiable_for_empty_objects() {
+        assert_eq!(RangeSpec::FromTo(0, 0).resolve(0), None);
+        assert_eq!(RangeSpec::From(0).resolve(0), None);
+        assert_eq!(RangeSpec::Suffix(1).resolve(0), None);
+        assert!(resolve_ranges(&[RangeSpec::From(0), RangeSpec::Suffix(5)], 0).is_empty());
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let specs = parse_range_header("bytes=50-59, 0-49, 25-74, 200-300, 80-89, 90-").unwrap();
+        assert_eq!(resolve_ranges(&specs, 100), vec![
+            ByteRange { start: 0, end: 74 },
+            ByteRange { start: 80, end: 99 },
+        ]);
+    }
+
+    #[test]
+    fn sums_requested_lengths_before_merging() {
+        let specs = parse_range_header("bytes=0-, 0-, -10, 200-").unwrap();
+        assert_eq!(requested_length(&specs, 100), 210);
+        assert_eq!(resolve_ranges(&specs, 100), vec![ByteRange { start: 0, end: 99 }]);
+        assert_eq!(requested_length(&parse_range_header("bytes=0-9, 20-29").unwrap(), 100), 20);
+    }
+
+    #[test]
+    fn formats_content_range() {
+        assert_eq!(ByteRange { start: 0, end: 9 }.content_range(100), "bytes 0-9/100");
+        assert_eq!(RangeSpec::Suffix(5).to_header_value(), "bytes=-5");
+    }
+
+    #[test]
+    fn slices_multipart_bodies() {
+        let body = b"0123456789";
+        let ranges = [ByteRange { start: 0, end: 1 }, ByteRange { start: 8, end: 9 }];
+        let result = multipart_body(body, &ranges, Some("text/plain"), "sep");
+
+        assert_eq!(String::from_utf8(result).unwrap(), concat!(
+            "\r\n--sep\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01",
+            "\r\n--sep\r\ncontent-type: text/plain\r\ncontent-range: bytes 8-9/10\r\n\r\n89",
+            "\r\n--sep--\r\n",
+        ));
+    }
+}
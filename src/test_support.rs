@@ -0,0 +1,6 @@
+use std::collections::HashMap;
+
+/// Object headers the way origins return them, with lowercase names.
+pub fn headers(values: &[(&str, &str)]) -> HashMap<String, String> {
+    values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}